@@ -78,9 +78,10 @@ pub fn command(_attr: TokenStream, item: TokenStream) -> TokenStream {
 
                         let ts = if is_option(ty) {
                             quote! {
-                                let #ident: Option<#inner_type> = match gramhive::extractors::Extractor::extract(
+                                let #ident: Option<#inner_type> = match gramhive::extractors::Extractor::extract_cached(
                                     &#extractor,
                                     std::sync::Arc::clone(&client),
+                                    &update_cache,
                                     &message,
                                     &command_input
                                 ).await {
@@ -93,9 +94,10 @@ pub fn command(_attr: TokenStream, item: TokenStream) -> TokenStream {
                             }
                         } else {
                             quote! {
-                                let #ident: #inner_type = match gramhive::extractors::Extractor::extract(
+                                let #ident: #inner_type = match gramhive::extractors::Extractor::extract_cached(
                                     &#extractor,
                                     std::sync::Arc::clone(&client),
+                                    &update_cache,
                                     &message,
                                     &command_input
                                 ).await {
@@ -120,6 +122,7 @@ pub fn command(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let mut has_message = false;
     let mut has_cmd_input = false;
     let mut has_client = false;
+    let mut has_update_cache = false;
     for input in new_inputs.iter() {
         if let FnArg::Typed(pat_type) = input {
             if let Pat::Ident(p) = &*pat_type.pat {
@@ -127,6 +130,7 @@ pub fn command(_attr: TokenStream, item: TokenStream) -> TokenStream {
                     "message" => has_message = true,
                     "command_input" => has_cmd_input = true,
                     "client" => has_client = true,
+                    "update_cache" => has_update_cache = true,
                     _ => {}
                 }
             }
@@ -142,6 +146,12 @@ pub fn command(_attr: TokenStream, item: TokenStream) -> TokenStream {
             parse_quote! { command_input: gramhive::commands::CommandInput },
         );
     }
+    if !has_update_cache {
+        new_inputs.insert(
+            0,
+            parse_quote! { update_cache: gramhive::cache::UpdateCache },
+        );
+    }
     if !has_client {
         new_inputs.insert(
             0,
@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use grammers_client::types::Message;
+
+/// All messages of an album, delivered as a single update.
+///
+/// Injected into the [`DependencyMap`](dptree::di::DependencyMap) as `Option<MediaGroup>`;
+/// use [`filter_media_group`](crate::filters::filter_media_group) to match on it.
+#[derive(Debug, Clone)]
+pub struct MediaGroup {
+    pub grouped_id: i64,
+    /// The album's messages, ordered by id.
+    pub messages: Vec<Message>,
+}
+
+struct Pending<T> {
+    parts: Vec<(i32, T)>,
+    generation: u64,
+}
+
+/// Collects the parts of albums until no new part arrived for `window`.
+pub(crate) struct AlbumBuffer<T = Message> {
+    window: Duration,
+    pending: Mutex<HashMap<(i64, i64), Pending<T>>>,
+}
+
+impl AlbumBuffer {
+    /// Buffer `message`, calling `on_ready` with the whole album once it is complete.
+    ///
+    /// Only the callback passed with the last part of an album is ever called.
+    pub(crate) fn push<F>(self: &Arc<Self>, message: Message, on_ready: F)
+    where
+        F: FnOnce(MediaGroup) + Send + 'static,
+    {
+        let Some(grouped_id) = message.grouped_id() else {
+            return;
+        };
+        let key = (message.chat().id(), grouped_id);
+        self.push_part(key, message.id(), message, move |messages| {
+            on_ready(MediaGroup {
+                grouped_id,
+                messages,
+            })
+        });
+    }
+}
+
+impl<T: Send + 'static> AlbumBuffer<T> {
+    pub(crate) fn new(window: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            window,
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Buffer the part `id` of the album `key`, calling `on_ready` with every part of
+    /// the album, ordered by id, once no new part arrived for `window`.
+    fn push_part<F>(self: &Arc<Self>, key: (i64, i64), id: i32, part: T, on_ready: F)
+    where
+        F: FnOnce(Vec<T>) + Send + 'static,
+    {
+        let generation = {
+            let mut pending = self.pending.lock().unwrap();
+            let entry = pending.entry(key).or_insert_with(|| Pending {
+                parts: Vec::new(),
+                generation: 0,
+            });
+            entry.parts.push((id, part));
+            entry.generation += 1;
+            entry.generation
+        };
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(this.window).await;
+
+            let mut parts = {
+                let mut pending = this.pending.lock().unwrap();
+                match pending.get(&key) {
+                    Some(entry) if entry.generation == generation => {
+                        pending.remove(&key).unwrap().parts
+                    }
+                    _ => return,
+                }
+            };
+            parts.sort_by_key(|(id, _)| *id);
+            on_ready(parts.into_iter().map(|(_, part)| part).collect());
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_parts_out_of_order() {
+        tokio::time::pause();
+        let buffer = AlbumBuffer::<i32>::new(Duration::from_millis(500));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        for id in [3, 1, 2] {
+            let tx = tx.clone();
+            buffer.push_part((1, 10), id, id, move |parts| {
+                let _ = tx.send(parts);
+            });
+            tokio::time::advance(Duration::from_millis(100)).await;
+        }
+        drop(tx);
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(rx.recv().await, Some(vec![1, 2, 3]));
+        assert_eq!(rx.recv().await, None);
+        assert!(buffer.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_albums_are_kept_apart() {
+        tokio::time::pause();
+        let buffer = AlbumBuffer::<i32>::new(Duration::from_millis(500));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        for (key, id) in [((1, 10), 2), ((2, 10), 5), ((1, 10), 1)] {
+            let tx = tx.clone();
+            buffer.push_part(key, id, id, move |parts| {
+                let _ = tx.send(parts);
+            });
+        }
+        drop(tx);
+
+        let mut albums = Vec::new();
+        while let Some(parts) = rx.recv().await {
+            albums.push(parts);
+        }
+        albums.sort();
+        assert_eq!(albums, [vec![1, 2], vec![5]]);
+    }
+}
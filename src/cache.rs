@@ -0,0 +1,155 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    slice,
+    sync::{Arc, Mutex},
+};
+
+use grammers_client::{
+    Client, InvocationError,
+    session::PackedType,
+    types::{Chat, Message, PackedChat},
+};
+use tokio::sync::OnceCell;
+
+type Slot<T> = Arc<OnceCell<T>>;
+
+/// Memoizes remote lookups for the lifetime of a single update.
+///
+/// [`Router::dispatch`](crate::router::Router::dispatch) inserts a fresh cache into the
+/// [`DependencyMap`](dptree::di::DependencyMap) for every update, so handlers and extractors
+/// working on the same update share it and each distinct lookup hits the API at most once.
+/// Failed lookups are not cached.
+#[derive(Clone, Default)]
+pub struct UpdateCache {
+    /// User and channel ids are separate namespaces, so chats are keyed by type too.
+    messages: Arc<Mutex<HashMap<(PackedType, i64, i32), Slot<Option<Message>>>>>,
+    chats: Arc<Mutex<HashMap<(PackedType, i64), Slot<Chat>>>>,
+}
+
+impl UpdateCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value under `key`, running `fetch` only if no earlier lookup succeeded.
+    async fn memoize<K, T, E, F, Fut>(
+        map: &Mutex<HashMap<K, Slot<T>>>,
+        key: K,
+        fetch: F,
+    ) -> Result<T, E>
+    where
+        K: Eq + Hash,
+        T: Clone,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let slot = map.lock().unwrap().entry(key).or_default().clone();
+        slot.get_or_try_init(fetch).await.cloned()
+    }
+
+    /// Fetch a message of `chat` by its id.
+    pub async fn get_message(
+        &self,
+        client: &Client,
+        chat: &Chat,
+        id: i32,
+    ) -> Result<Option<Message>, InvocationError> {
+        let packed = chat.pack();
+        Self::memoize(&self.messages, (packed.ty, packed.id, id), || async {
+            client
+                .get_messages_by_id(packed, slice::from_ref(&id))
+                .await
+                .map(|msgs| msgs.into_iter().flatten().next())
+        })
+        .await
+    }
+
+    /// Fetch the message `message` replies to, if any.
+    pub async fn get_reply(
+        &self,
+        client: &Client,
+        message: &Message,
+    ) -> Result<Option<Message>, InvocationError> {
+        match message.reply_to_message_id() {
+            Some(reply_msg_id) => {
+                self.get_message(client, &message.chat(), reply_msg_id)
+                    .await
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Resolve a packed chat (e.g. a sender) into a full [`Chat`].
+    pub async fn unpack_chat(
+        &self,
+        client: &Client,
+        packed_chat: PackedChat,
+    ) -> Result<Chat, InvocationError> {
+        Self::memoize(&self.chats, (packed_chat.ty, packed_chat.id), || {
+            client.unpack_chat(packed_chat)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    type Map = Mutex<HashMap<(PackedType, i64), Slot<i64>>>;
+
+    async fn lookup(map: &Map, key: (PackedType, i64), calls: &AtomicUsize) -> Result<i64, ()> {
+        let value = key.1 * 10;
+        UpdateCache::memoize(map, key, || async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(value)
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_repeated_lookup_fetches_once() {
+        let map = Map::default();
+        let calls = AtomicUsize::new(0);
+        let key = (PackedType::User, 1);
+        let (first, second) = tokio::join!(lookup(&map, key, &calls), lookup(&map, key, &calls));
+        assert_eq!((first, second), (Ok(10), Ok(10)));
+        assert_eq!(lookup(&map, key, &calls).await, Ok(10));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_types_do_not_collide() {
+        let map = Map::default();
+        let calls = AtomicUsize::new(0);
+        lookup(&map, (PackedType::User, 1), &calls).await.unwrap();
+        lookup(&map, (PackedType::Broadcast, 1), &calls)
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_not_cached() {
+        let map = Map::default();
+        let calls = AtomicUsize::new(0);
+        let fail = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<i64, _>(())
+        };
+        assert!(
+            UpdateCache::memoize(&map, (PackedType::User, 1), fail)
+                .await
+                .is_err()
+        );
+        assert!(
+            UpdateCache::memoize(&map, (PackedType::User, 1), fail)
+                .await
+                .is_err()
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
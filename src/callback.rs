@@ -0,0 +1,242 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, OnceLock, RwLock},
+};
+
+use async_trait::async_trait;
+use lru::LruCache;
+use rand::{Rng, distr::Alphanumeric};
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::sync::OnceCell;
+
+use crate::errors::CallbackError;
+
+/// Telegram's limit on the size of inline button callback data.
+pub const MAX_CALLBACK_DATA_LEN: usize = 64;
+
+const INLINE_TAG: u8 = b'i';
+const TOKEN_TAG: u8 = b't';
+const TOKEN_LEN: usize = 12;
+
+/// Storage for callback payloads too large to fit in the callback data itself.
+#[async_trait]
+pub trait CallbackStore: Send + Sync + 'static {
+    async fn put(&self, token: &str, payload: Vec<u8>) -> Result<(), CallbackError>;
+
+    async fn get(&self, token: &str) -> Result<Option<Vec<u8>>, CallbackError>;
+}
+
+/// In-memory store that keeps the most recently created payloads.
+pub struct LruCallbackStore {
+    cache: Mutex<LruCache<String, Vec<u8>>>,
+}
+
+impl LruCallbackStore {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            cache: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl Default for LruCallbackStore {
+    fn default() -> Self {
+        Self::new(NonZeroUsize::new(10_000).unwrap())
+    }
+}
+
+#[async_trait]
+impl CallbackStore for LruCallbackStore {
+    async fn put(&self, token: &str, payload: Vec<u8>) -> Result<(), CallbackError> {
+        self.cache.lock().unwrap().put(token.to_string(), payload);
+        Ok(())
+    }
+
+    async fn get(&self, token: &str) -> Result<Option<Vec<u8>>, CallbackError> {
+        Ok(self.cache.lock().unwrap().get(token).cloned())
+    }
+}
+
+/// Redis-backed store, for payloads that must survive restarts or be shared between bots.
+///
+/// The connection is opened on first use and shared by every later call.
+pub struct RedisCallbackStore {
+    client: redis::Client,
+    conn: OnceCell<MultiplexedConnection>,
+    prefix: String,
+    ttl_secs: u64,
+}
+
+impl RedisCallbackStore {
+    pub fn new(client: redis::Client, ttl_secs: u64) -> Self {
+        Self {
+            client,
+            conn: OnceCell::new(),
+            prefix: "gramhive:callback:".to_string(),
+            ttl_secs,
+        }
+    }
+
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    async fn connection(&self) -> Result<MultiplexedConnection, CallbackError> {
+        let conn = self
+            .conn
+            .get_or_try_init(|| self.client.get_multiplexed_async_connection())
+            .await?;
+        Ok(conn.clone())
+    }
+}
+
+#[async_trait]
+impl CallbackStore for RedisCallbackStore {
+    async fn put(&self, token: &str, payload: Vec<u8>) -> Result<(), CallbackError> {
+        let mut conn = self.connection().await?;
+        let _: () = conn
+            .set_ex(format!("{}{}", self.prefix, token), payload, self.ttl_secs)
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, token: &str) -> Result<Option<Vec<u8>>, CallbackError> {
+        let mut conn = self.connection().await?;
+        Ok(conn.get(format!("{}{}", self.prefix, token)).await?)
+    }
+}
+
+static STORE: OnceLock<RwLock<Arc<dyn CallbackStore>>> = OnceLock::new();
+
+fn init_store() -> &'static RwLock<Arc<dyn CallbackStore>> {
+    STORE.get_or_init(|| RwLock::new(Arc::new(LruCallbackStore::default())))
+}
+
+/// Encoding of typed values into inline button callback data.
+///
+/// Data is laid out as `{namespace}:{tag}{body}`. Values are serialized with `postcard`
+/// and stored inline when they fit in [`MAX_CALLBACK_DATA_LEN`] bytes; otherwise they
+/// are moved to the configured [`CallbackStore`] and the body is a short token.
+pub struct CallbackData;
+
+impl CallbackData {
+    pub fn get_store() -> Arc<dyn CallbackStore> {
+        init_store().read().unwrap().clone()
+    }
+
+    pub fn set_store<S: CallbackStore>(store: S) {
+        *init_store().write().unwrap() = Arc::new(store);
+    }
+
+    /// Encode `value` under `namespace`, ready to be used as button data.
+    ///
+    /// `namespace` must not contain `:`, which separates it from the body.
+    pub async fn encode<T: Serialize>(
+        namespace: &str,
+        value: &T,
+    ) -> Result<Vec<u8>, CallbackError> {
+        if namespace.contains(':') {
+            return Err(CallbackError::InvalidNamespace(namespace.to_string()));
+        }
+        let payload = postcard::to_allocvec(value)?;
+
+        let mut data = Vec::with_capacity(MAX_CALLBACK_DATA_LEN);
+        data.extend_from_slice(namespace.as_bytes());
+        data.push(b':');
+
+        if data.len() + 1 + payload.len() <= MAX_CALLBACK_DATA_LEN {
+            data.push(INLINE_TAG);
+            data.extend_from_slice(&payload);
+            return Ok(data);
+        }
+
+        let token: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LEN)
+            .map(char::from)
+            .collect();
+        data.push(TOKEN_TAG);
+        data.extend_from_slice(token.as_bytes());
+        if data.len() > MAX_CALLBACK_DATA_LEN {
+            return Err(CallbackError::NamespaceTooLong(namespace.to_string()));
+        }
+
+        Self::get_store().put(&token, payload).await?;
+        Ok(data)
+    }
+
+    /// Decode data produced by [`CallbackData::encode`].
+    ///
+    /// Returns `Ok(None)` if `data` belongs to a different namespace.
+    pub async fn decode<T: DeserializeOwned>(
+        namespace: &str,
+        data: &[u8],
+    ) -> Result<Option<T>, CallbackError> {
+        let Some(rest) = data
+            .strip_prefix(namespace.as_bytes())
+            .and_then(|rest| rest.strip_prefix(b":"))
+        else {
+            return Ok(None);
+        };
+
+        match rest.split_first() {
+            Some((&INLINE_TAG, payload)) => Ok(Some(postcard::from_bytes(payload)?)),
+            Some((&TOKEN_TAG, token)) => {
+                let token = std::str::from_utf8(token).map_err(|_| CallbackError::Malformed)?;
+                let payload = Self::get_store()
+                    .get(token)
+                    .await?
+                    .ok_or(CallbackError::Expired)?;
+                Ok(Some(postcard::from_bytes(&payload)?))
+            }
+            _ => Err(CallbackError::Malformed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_inline_round_trip() {
+        let data = CallbackData::encode("vote", &(7u32, true)).await.unwrap();
+        assert_eq!(&data[..6], b"vote:i");
+        let decoded: Option<(u32, bool)> = CallbackData::decode("vote", &data).await.unwrap();
+        assert_eq!(decoded, Some((7, true)));
+    }
+
+    #[tokio::test]
+    async fn test_token_round_trip() {
+        let value = "x".repeat(200);
+        let data = CallbackData::encode("page", &value).await.unwrap();
+        assert_eq!(&data[..6], b"page:t");
+        assert!(data.len() <= MAX_CALLBACK_DATA_LEN);
+        let decoded: Option<String> = CallbackData::decode("page", &data).await.unwrap();
+        assert_eq!(decoded, Some(value));
+    }
+
+    #[tokio::test]
+    async fn test_namespace_mismatch() {
+        let data = CallbackData::encode("vote", &1u8).await.unwrap();
+        let decoded: Option<u8> = CallbackData::decode("page", &data).await.unwrap();
+        assert_eq!(decoded, None);
+        let decoded: Option<u8> = CallbackData::decode("vo", &data).await.unwrap();
+        assert_eq!(decoded, None);
+    }
+
+    #[tokio::test]
+    async fn test_namespace_too_long() {
+        let namespace = "n".repeat(MAX_CALLBACK_DATA_LEN);
+        let result = CallbackData::encode(&namespace, &"x".repeat(200)).await;
+        assert!(matches!(result, Err(CallbackError::NamespaceTooLong(_))));
+    }
+
+    #[tokio::test]
+    async fn test_namespace_with_colon() {
+        let result = CallbackData::encode("a:b", &1u8).await;
+        assert!(matches!(result, Err(CallbackError::InvalidNamespace(_))));
+    }
+}
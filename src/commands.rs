@@ -5,6 +5,7 @@ use std::{
     collections::HashMap,
     pin::Pin,
     sync::{Arc, OnceLock, RwLock},
+    time::Duration,
 };
 
 use crate::{GenericResult, router::Router};
@@ -72,6 +73,15 @@ pub struct CommandMeta {
     pub description: Option<&'static str>,
     pub module: Option<&'static str>,
     pub sig: Option<&'static str>,
+    /// Run the message text through [`normalize`](crate::normalize::normalize) before matching.
+    pub normalize: bool,
+    /// Whether running the command again is harmless. Non-idempotent commands are not
+    /// re-run when the user edits their message.
+    pub idempotent: bool,
+    /// How long the handler may run before it fails with
+    /// [`DispatchError::Timeout`](crate::errors::DispatchError::Timeout). Applied by
+    /// [`HandlerExt::filter_scoped_command`](crate::filters::HandlerExt::filter_scoped_command).
+    pub timeout: Option<Duration>,
     pub regex: Regex,
 }
 
@@ -84,6 +94,9 @@ impl CommandMeta {
         description: Option<&'static str>,
         module: Option<&'static str>,
         sig: Option<&'static str>,
+        #[builder(default)] normalize: bool,
+        #[builder(default = true)] idempotent: bool,
+        timeout: Option<Duration>,
     ) -> Self {
         let prefix_pattern = CommandMeta::get_prefixes()
             .iter()
@@ -103,6 +116,9 @@ impl CommandMeta {
             description,
             module,
             sig,
+            normalize,
+            idempotent,
+            timeout,
             regex,
         }
     }
@@ -140,7 +156,11 @@ impl CommandMeta {
         self.extras.get(key)?.downcast_ref::<T>()
     }
 
-    pub fn register(self, router: &mut Router) -> Self {
+    /// Add the command to `router`, taking the router's module if it does not name one.
+    pub fn register(mut self, router: &mut Router) -> Self {
+        if self.module.is_none() {
+            self.module = router.module();
+        }
         router.add_command(self.clone());
         self
     }
@@ -156,6 +176,8 @@ impl CommandMeta {
 
 #[derive(Debug, Clone)]
 pub struct CommandInput {
+    /// The message text as sent, before any normalization.
+    pub raw: String,
     pub prefix: String,
     pub cmd: String,
     pub input: Option<String>,
@@ -0,0 +1,264 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use dptree::di::DependencyMap;
+use futures::future::BoxFuture;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+/// What to do with an update when the concurrency limit is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SaturationPolicy {
+    /// Stop reading updates until a dispatch finishes.
+    #[default]
+    Wait,
+    /// Keep reading updates into a backlog of at most `backlog` updates, dropping the
+    /// oldest one when it overflows. Dropped updates are reported like with
+    /// [`SaturationPolicy::DropAndNotify`].
+    DropOldest { backlog: usize },
+    /// Drop the update and emit [`Event::UpdateDropped`](crate::Event::UpdateDropped).
+    DropAndNotify,
+}
+
+/// Permits held for as long as a dispatch runs.
+pub(crate) struct Permits {
+    _held: Vec<OwnedSemaphorePermit>,
+}
+
+pub(crate) type Start = Box<dyn FnOnce(Permits) -> BoxFuture<'static, ()> + Send>;
+
+/// Admits dispatches of a single client under its own and the swarm-wide limit.
+pub(crate) struct Limiter {
+    policy: SaturationPolicy,
+    local: Option<Arc<Semaphore>>,
+    global: Option<Arc<Semaphore>>,
+    backlog: Mutex<VecDeque<(Start, DependencyMap)>>,
+    ready: Notify,
+}
+
+impl Limiter {
+    /// The backlog of [`SaturationPolicy::DropOldest`] is drained until `shutdown` is
+    /// cancelled.
+    pub(crate) fn new(
+        policy: SaturationPolicy,
+        local: Option<usize>,
+        global: Option<Arc<Semaphore>>,
+        shutdown: CancellationToken,
+    ) -> Arc<Self> {
+        let limiter = Arc::new(Self {
+            policy,
+            local: local.map(|limit| Arc::new(Semaphore::new(limit))),
+            global,
+            backlog: Mutex::new(VecDeque::new()),
+            ready: Notify::new(),
+        });
+        if matches!(policy, SaturationPolicy::DropOldest { .. }) {
+            tokio::spawn(limiter.clone().drain_backlog(shutdown));
+        }
+        limiter
+    }
+
+    /// Start the dispatch of the update in `deps` once permits are available, according
+    /// to the policy.
+    ///
+    /// Returns the dependencies of the updates that were dropped: this one, or older ones
+    /// evicted from the backlog.
+    pub(crate) async fn submit(&self, start: Start, deps: DependencyMap) -> Vec<DependencyMap> {
+        match self.policy {
+            SaturationPolicy::Wait => {
+                let permits = self.acquire().await;
+                start(permits).await;
+                Vec::new()
+            }
+            SaturationPolicy::DropOldest { backlog } => {
+                let mut evicted = Vec::new();
+                {
+                    let mut pending = self.backlog.lock().unwrap();
+                    pending.push_back((start, deps));
+                    while pending.len() > backlog.max(1) {
+                        evicted.extend(pending.pop_front().map(|(_, deps)| deps));
+                    }
+                }
+                self.ready.notify_one();
+                evicted
+            }
+            SaturationPolicy::DropAndNotify => match self.try_acquire() {
+                Some(permits) => {
+                    start(permits).await;
+                    Vec::new()
+                }
+                None => vec![deps],
+            },
+        }
+    }
+
+    async fn acquire(&self) -> Permits {
+        // The client's own limit comes first so that a busy client does not hold
+        // swarm-wide permits while it waits for its own.
+        let mut permits = Vec::with_capacity(2);
+        for semaphore in [&self.local, &self.global].into_iter().flatten() {
+            permits.push(semaphore.clone().acquire_owned().await.unwrap());
+        }
+        Permits { _held: permits }
+    }
+
+    fn try_acquire(&self) -> Option<Permits> {
+        let mut permits = Vec::with_capacity(2);
+        for semaphore in [&self.local, &self.global].into_iter().flatten() {
+            permits.push(semaphore.clone().try_acquire_owned().ok()?);
+        }
+        Some(Permits { _held: permits })
+    }
+
+    async fn drain_backlog(self: Arc<Self>, shutdown: CancellationToken) {
+        loop {
+            let next = async {
+                while self.backlog.lock().unwrap().is_empty() {
+                    self.ready.notified().await;
+                }
+                // Only take permits for an update that is waiting, and pop it once they
+                // are held so that it can still be dropped for a newer one meanwhile.
+                let permits = self.acquire().await;
+                let (start, _) = self.backlog.lock().unwrap().pop_front()?;
+                Some((start, permits))
+            };
+            let next = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                next = next => next,
+            };
+            if let Some((start, permits)) = next {
+                start(permits).await;
+            }
+        }
+        self.backlog.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{FutureExt, future};
+    use tokio::sync::{mpsc, oneshot};
+
+    use super::*;
+
+    /// A dispatch of update `id` that reports its start and holds its permits until
+    /// `release` fires or is dropped.
+    fn job(
+        id: i32,
+        started: &mpsc::UnboundedSender<i32>,
+    ) -> (Start, DependencyMap, oneshot::Sender<()>) {
+        let (release, released) = oneshot::channel();
+        let started = started.clone();
+        let start: Start = Box::new(move |permits| {
+            tokio::spawn(async move {
+                let _ = started.send(id);
+                let _ = released.await;
+                drop(permits);
+            });
+            future::ready(()).boxed()
+        });
+        (start, dptree::deps![id], release)
+    }
+
+    fn ids(dropped: &[DependencyMap]) -> Vec<i32> {
+        dropped.iter().map(|deps| *deps.get::<i32>()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_wait_blocks_until_a_dispatch_finishes() {
+        tokio::time::pause();
+        let limiter = Limiter::new(
+            SaturationPolicy::Wait,
+            Some(1),
+            None,
+            CancellationToken::new(),
+        );
+        let (tx, mut started) = mpsc::unbounded_channel();
+
+        let (start, deps, first) = job(1, &tx);
+        assert!(limiter.submit(start, deps).await.is_empty());
+        assert_eq!(started.recv().await, Some(1));
+
+        let (start, deps, _second) = job(2, &tx);
+        let mut submit = limiter.submit(start, deps).boxed();
+        let blocked = tokio::time::timeout(Duration::from_secs(1), &mut submit).await;
+        assert!(blocked.is_err());
+
+        drop(first);
+        assert!(submit.await.is_empty());
+        assert_eq!(started.recv().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_drop_and_notify_drops_the_new_update() {
+        let limiter = Limiter::new(
+            SaturationPolicy::DropAndNotify,
+            Some(1),
+            None,
+            CancellationToken::new(),
+        );
+        let (tx, mut started) = mpsc::unbounded_channel();
+
+        let (start, deps, first) = job(1, &tx);
+        assert!(limiter.submit(start, deps).await.is_empty());
+        assert_eq!(started.recv().await, Some(1));
+
+        let (start, deps, _second) = job(2, &tx);
+        assert_eq!(ids(&limiter.submit(start, deps).await), [2]);
+
+        drop(first);
+        tokio::task::yield_now().await;
+        let (start, deps, _third) = job(3, &tx);
+        assert!(limiter.submit(start, deps).await.is_empty());
+        assert_eq!(started.recv().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_evicts_from_the_backlog() {
+        let limiter = Limiter::new(
+            SaturationPolicy::DropOldest { backlog: 1 },
+            Some(1),
+            None,
+            CancellationToken::new(),
+        );
+        let (tx, mut started) = mpsc::unbounded_channel();
+
+        let (start, deps, first) = job(1, &tx);
+        assert!(limiter.submit(start, deps).await.is_empty());
+        assert_eq!(started.recv().await, Some(1));
+
+        let (start, deps, _second) = job(2, &tx);
+        assert!(limiter.submit(start, deps).await.is_empty());
+        let (start, deps, _third) = job(3, &tx);
+        assert_eq!(ids(&limiter.submit(start, deps).await), [2]);
+
+        drop(first);
+        assert_eq!(started.recv().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_stops_on_shutdown() {
+        tokio::time::pause();
+        let shutdown = CancellationToken::new();
+        let limiter = Limiter::new(
+            SaturationPolicy::DropOldest { backlog: 1 },
+            Some(1),
+            None,
+            shutdown.clone(),
+        );
+        assert_eq!(Arc::strong_count(&limiter), 2);
+
+        shutdown.cancel();
+        let stopped = tokio::time::timeout(Duration::from_secs(1), async {
+            while Arc::strong_count(&limiter) > 1 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await;
+        assert!(stopped.is_ok());
+    }
+}
@@ -1,7 +1,11 @@
-use grammers_client::InvocationError;
+use std::{error::Error as StdError, ops::ControlFlow};
+
+use dptree::{Handler, di::DependencyMap};
+use grammers_client::{InvocationError, Update};
 use thiserror::Error;
+use tracing::{error, warn};
 
-use crate::commands::CommandInput;
+use crate::{SharedError, commands::CommandInput, router::HandlerPath, tg_html::TgHtml};
 
 #[derive(Error, Debug)]
 pub enum ExtractionError {
@@ -41,3 +45,218 @@ impl ExtractionError {
         }
     }
 }
+
+#[derive(Error, Debug)]
+pub enum CallbackError {
+    #[error("namespace `{0}` leaves no room for callback data")]
+    NamespaceTooLong(String),
+
+    #[error("namespace `{0}` must not contain `:`")]
+    InvalidNamespace(String),
+
+    #[error("malformed callback data")]
+    Malformed,
+
+    #[error("callback payload expired from the store")]
+    Expired,
+
+    #[error("callback payload (de)serialization error")]
+    Serde(#[from] postcard::Error),
+
+    #[error("callback store error")]
+    Redis(#[from] redis::RedisError),
+}
+
+/// Identifies the handler a [`DispatchError`] happened in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandlerId {
+    /// The handler at this path, starting from the router that was dispatched.
+    Path(HandlerPath),
+    /// The handler of the command with this name.
+    Command(&'static str),
+}
+
+impl std::fmt::Display for HandlerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandlerId::Path(path) => write!(f, "handler {path}"),
+            HandlerId::Command(cmd) => write!(f, "command `{cmd}`"),
+        }
+    }
+}
+
+/// Failures of the dispatch machinery itself, delivered to the error handler.
+#[derive(Error, Debug)]
+pub enum DispatchError {
+    #[error("{handler} timed out after {timeout:?}")]
+    Timeout {
+        handler: HandlerId,
+        timeout: std::time::Duration,
+    },
+
+    #[error(
+        "{handler} panicked at {}: {message}",
+        .location.as_deref().unwrap_or("unknown location")
+    )]
+    Panic {
+        handler: HandlerId,
+        message: String,
+        location: Option<String>,
+    },
+}
+
+/// The error type of handlers.
+///
+/// Like `anyhow::Error`, it does not implement [`std::error::Error`] itself, so that
+/// every error type that does converts with `?`. Telegram, argument, dispatch and
+/// timeout errors land in their own variants, everything else in [`Error::Internal`].
+/// An `anyhow::Error` is not a [`std::error::Error`]; wrap it with `Error::Internal`.
+#[derive(Debug)]
+pub enum Error {
+    /// A message that is safe to show to the user, e.g. "You are not allowed to do that".
+    User(TgHtml),
+    Argument(ArgumentError),
+    Telegram(InvocationError),
+    /// An operation of the handler timed out.
+    Timeout,
+    Dispatch(DispatchError),
+    /// Handlers of a router failed and their errors were already passed to the error
+    /// handler. Router-wide middlewares get this from [`Next::run`](crate::Next::run),
+    /// included routers yield it, and finalizers get it when several handlers failed.
+    Handled(Vec<SharedError>),
+    Internal(anyhow::Error),
+}
+
+impl Error {
+    pub fn user(message: TgHtml) -> Self {
+        Error::User(message)
+    }
+
+    pub fn internal<E: StdError + Send + Sync + 'static>(error: E) -> Self {
+        Error::Internal(error.into())
+    }
+
+    /// An internal error with just a message.
+    pub fn msg<M>(message: M) -> Self
+    where
+        M: std::fmt::Display + std::fmt::Debug + Send + Sync + 'static,
+    {
+        Error::Internal(anyhow::Error::msg(message))
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::User(text) => write!(f, "{text}"),
+            Error::Argument(err) => write!(f, "{err}"),
+            Error::Telegram(err) => write!(f, "telegram error: {err}"),
+            Error::Timeout => f.write_str("operation timed out"),
+            Error::Dispatch(err) => write!(f, "{err}"),
+            Error::Handled(errors) => write!(f, "{} handler(s) failed", errors.len()),
+            Error::Internal(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl<E> From<E> for Error
+where
+    E: StdError + Send + Sync + 'static,
+{
+    fn from(error: E) -> Self {
+        let error: Box<dyn StdError + Send + Sync> = Box::new(error);
+        let error = match error.downcast::<InvocationError>() {
+            Ok(err) => return Error::Telegram(*err),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<ArgumentError>() {
+            Ok(err) => return Error::Argument(*err),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<DispatchError>() {
+            Ok(err) => return Error::Dispatch(*err),
+            Err(error) => error,
+        };
+        if error.is::<tokio::time::error::Elapsed>() {
+            return Error::Timeout;
+        }
+        Error::Internal(anyhow::Error::from_boxed(error))
+    }
+}
+
+impl From<Error> for anyhow::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Internal(err) => err,
+            other => anyhow::Error::msg(other),
+        }
+    }
+}
+
+/// The error handler routers use unless one is set.
+///
+/// Replies with [`Error::User`] messages and logs every other error together with the
+/// chat and message it happened in.
+pub fn default_error_handler() -> Handler<'static, DependencyMap, ()> {
+    dptree::from_fn(|deps: DependencyMap, _cont| async move {
+        let Some(error) = deps.clone().remove::<SharedError>() else {
+            return ControlFlow::Break(());
+        };
+        let update = deps.clone().remove::<Update>();
+        let message = match update.as_deref() {
+            Some(Update::NewMessage(message) | Update::MessageEdited(message)) => Some(message),
+            _ => None,
+        };
+
+        match (&**error, message) {
+            (Error::User(text), Some(message)) => {
+                if let Err(err) = message.reply(text.clone()).await {
+                    warn!(
+                        chat_id = message.chat().id(),
+                        "failed to send error reply: {err}"
+                    );
+                }
+            }
+            (error, Some(message)) => {
+                error!(
+                    chat_id = message.chat().id(),
+                    message_id = message.id(),
+                    sender_id = message.sender().map(|sender| sender.id()),
+                    "handler failed: {error}"
+                );
+            }
+            (error, None) => error!("handler failed: {error}"),
+        }
+        ControlFlow::Break(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn convert<E: StdError + Send + Sync + 'static>(error: E) -> Error {
+        error.into()
+    }
+
+    #[test]
+    fn test_from_keeps_known_variants() {
+        let timeout = DispatchError::Timeout {
+            handler: HandlerId::Path(HandlerPath(vec![0])),
+            timeout: Duration::from_secs(1),
+        };
+        assert!(matches!(convert(timeout), Error::Dispatch(_)));
+        assert!(matches!(
+            convert(std::io::Error::other("disk full")),
+            Error::Internal(_)
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Error::msg("boom").to_string(), "boom");
+        assert_eq!(Error::Timeout.to_string(), "operation timed out");
+    }
+}
@@ -1,14 +1,137 @@
-use std::ops::ControlFlow;
+use std::{
+    ops::ControlFlow,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
-use dptree::di::DependencyMap;
+use dptree::{Handler, di::DependencyMap};
+use grammers_client::InvocationError;
+
+use crate::SharedError;
 
 pub enum Event<'a> {
-    BeginDispatch { deps: &'a mut DependencyMap },
-    EndDispatch { deps: DependencyMap },
+    /// An update arrived, before anything else looked at it.
+    UpdateReceived {
+        deps: &'a DependencyMap,
+    },
+    /// Returning [`ControlFlow::Break`] from a listener cancels the dispatch.
+    BeginDispatch {
+        deps: &'a mut DependencyMap,
+    },
+    /// The handler at index `handler` of the router passed its filters and is about to
+    /// run its endpoint.
+    ///
+    /// Handlers that do not chain [`matched`] report this only after the endpoint ran.
+    HandlerMatched {
+        handler: usize,
+    },
+    /// `elapsed` is measured from [`Event::HandlerMatched`], so filters are not counted.
+    HandlerFinished {
+        handler: usize,
+        elapsed: Duration,
+    },
+    HandlerFailed {
+        handler: usize,
+        error: &'a SharedError,
+    },
+    /// A Telegram call failed and is retried after `delay`. `handler` is the index of
+    /// the handler being re-run, or `None` for a call made through a
+    /// [`RetryClient`](crate::retry::RetryClient).
+    Retrying {
+        handler: Option<usize>,
+        attempt: u32,
+        delay: Duration,
+        error: &'a InvocationError,
+    },
+    /// No handler matched the update.
+    Unhandled {
+        deps: &'a DependencyMap,
+    },
+    EndDispatch {
+        deps: DependencyMap,
+    },
+    /// The update was dropped because the concurrency limit was reached.
+    UpdateDropped {
+        deps: &'a DependencyMap,
+    },
+    ClientDisconnected {
+        error: &'a InvocationError,
+    },
 }
 
 #[async_trait]
 pub trait EventListener: Send + Sync + 'static {
+    /// Only the result for [`Event::BeginDispatch`] matters; it is ignored otherwise.
     async fn handle(&self, event: &Event) -> ControlFlow<()>;
 }
+
+/// Deliver `event` to `listeners` in order.
+///
+/// A listener breaking out of [`Event::BeginDispatch`] stops the delivery and cancels
+/// the dispatch; every other event reaches all listeners.
+pub(crate) async fn emit(
+    listeners: &[Arc<dyn EventListener>],
+    event: Event<'_>,
+) -> ControlFlow<()> {
+    for listener in listeners {
+        if listener.handle(&event).await.is_break() && matches!(event, Event::BeginDispatch { .. })
+        {
+            return ControlFlow::Break(());
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+/// Reports [`Event::HandlerMatched`] for the handler being run, injected by the router.
+#[derive(Clone)]
+pub(crate) struct MatchSignal {
+    listeners: Vec<Arc<dyn EventListener>>,
+    handler: usize,
+    matched_at: Arc<OnceLock<Instant>>,
+}
+
+impl MatchSignal {
+    pub(crate) fn new(listeners: Vec<Arc<dyn EventListener>>, handler: usize) -> Self {
+        Self {
+            listeners,
+            handler,
+            matched_at: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /// Emit [`Event::HandlerMatched`] unless it was already emitted.
+    pub(crate) async fn fire(&self) {
+        if self.matched_at.set(Instant::now()).is_ok() {
+            let _ = emit(
+                &self.listeners,
+                Event::HandlerMatched {
+                    handler: self.handler,
+                },
+            )
+            .await;
+        }
+    }
+
+    /// When the handler matched, if it did yet.
+    pub(crate) fn matched_at(&self) -> Option<Instant> {
+        self.matched_at.get().copied()
+    }
+}
+
+/// Marks the point where a handler's filters passed. Chain it right before the endpoint.
+///
+/// Emits [`Event::HandlerMatched`] and starts the clock for
+/// [`Event::HandlerFinished`], so neither counts the time spent in filters.
+#[must_use]
+pub fn matched<Output>() -> Handler<'static, DependencyMap, Output>
+where
+    Output: Send + Sync + 'static,
+{
+    dptree::from_fn(|deps: DependencyMap, cont| async move {
+        if let Some(signal) = deps.clone().remove::<MatchSignal>() {
+            signal.fire().await;
+        }
+        cont(deps).await
+    })
+}
@@ -1,11 +1,12 @@
-use core::slice;
 use std::{marker::PhantomData, sync::Arc};
 
 use async_trait::async_trait;
-use grammers_client::{Client, grammers_tl_types as tl, types::Message};
+use grammers_client::{Client, types::Message};
 use regex::Regex;
 
-use crate::{commands::CommandInput, errors::ExtractionError};
+use crate::{
+    cache::UpdateCache, commands::CommandInput, errors::ExtractionError, normalize::normalize,
+};
 
 #[async_trait]
 pub trait Extractor: Send + Sync {
@@ -17,6 +18,18 @@ pub trait Extractor: Send + Sync {
         message: &Message,
         command_input: &CommandInput,
     ) -> Result<Self::Output, ExtractionError>;
+
+    /// Like [`Extractor::extract`], with the update's [`UpdateCache`] for lookups that
+    /// other extractors and handlers may repeat. Defaults to `extract`.
+    async fn extract_cached(
+        &self,
+        client: Arc<Client>,
+        _cache: &UpdateCache,
+        message: &Message,
+        command_input: &CommandInput,
+    ) -> Result<Self::Output, ExtractionError> {
+        self.extract(client, message, command_input).await
+    }
 }
 
 pub struct RegexExtractor<F> {
@@ -36,9 +49,15 @@ where
         &self,
         _client: Arc<Client>,
         message: &Message,
-        _cmd: &CommandInput,
+        command_input: &CommandInput,
     ) -> Result<Self::Output, ExtractionError> {
-        if let Some(mat) = self.regex.captures(message.text()).and_then(|c| c.get(0)) {
+        let text = if command_input.meta.normalize {
+            normalize(message.text())
+        } else {
+            message.text().to_string()
+        };
+
+        if let Some(mat) = self.regex.captures(&text).and_then(|c| c.get(0)) {
             let s = mat.as_str();
             s.parse::<F>().map_err(|_| ExtractionError::Mismatched {
                 expected: std::any::type_name::<F>().to_string(),
@@ -99,24 +118,23 @@ impl Extractor for ReplyExtractor {
         &self,
         client: Arc<Client>,
         message: &Message,
+        cmd: &CommandInput,
+    ) -> Result<Self::Output, ExtractionError> {
+        self.extract_cached(client, &UpdateCache::new(), message, cmd)
+            .await
+    }
+
+    async fn extract_cached(
+        &self,
+        client: Arc<Client>,
+        cache: &UpdateCache,
+        message: &Message,
         _cmd: &CommandInput,
     ) -> Result<Self::Output, ExtractionError> {
-        if let Some(tl::enums::MessageReplyHeader::Header(reply_header)) = message.reply_header() {
-            if let Some(reply_msg_id) = reply_header.reply_to_msg_id {
-                let message = client
-                    .get_messages_by_id(message.chat(), slice::from_ref(&reply_msg_id))
-                    .await
-                    .map(|msgs| msgs.into_iter().flatten().next());
-                match message {
-                    Ok(Some(msg)) => Ok(msg),
-                    Ok(None) => Err(ExtractionError::Missing),
-                    Err(err) => Err(ExtractionError::Invocation(err)),
-                }
-            } else {
-                return Err(ExtractionError::Missing);
-            }
-        } else {
-            return Err(ExtractionError::Missing);
+        match cache.get_reply(&client, message).await {
+            Ok(Some(msg)) => Ok(msg),
+            Ok(None) => Err(ExtractionError::Missing),
+            Err(err) => Err(ExtractionError::Invocation(err)),
         }
     }
 }
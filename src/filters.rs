@@ -1,9 +1,38 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::{ControlFlow, Range, RangeBounds},
+    sync::{Arc, Mutex, RwLock},
+};
+
 use dptree::{Handler, di::DependencyMap};
-use grammers_client::{Update, types::update::Message};
+use grammers_client::{
+    Client, Update, grammers_tl_types as tl,
+    types::{CallbackQuery, Chat, Media, media::Document, update::Message},
+};
+use regex::{Regex, RegexBuilder};
+use serde::de::DeserializeOwned;
+use tracing::warn;
 
 use crate::{
     GenericResult,
+    album::MediaGroup,
+    callback::CallbackData,
     commands::{CommandInput, CommandMeta},
+    event::matched,
+    media::{
+        Animation, Audio, DocumentKind, Video, VideoNote, Voice, document_duration, document_kind,
+        document_size, glob_match,
+    },
+    middleware::module_scope,
+    normalize::normalize,
+    permissions::{AdminRight, ChatPermissions},
+    replies::TextEdit,
+    service::{
+        ChatCreated, ChatMigrated, ChatPhotoChanged, ChatTitleChanged, GroupCallEvent,
+        MemberJoined, MemberLeft, PinnedMessage, ServiceEvent,
+    },
+    swarm::Me,
+    timeout::command_timeout,
 };
 
 pub type DpResult = dptree::Handler<'static, DependencyMap, GenericResult>;
@@ -11,15 +40,41 @@ pub type DpResult = dptree::Handler<'static, DependencyMap, GenericResult>;
 pub trait HandlerExt<Output> {
     #[must_use]
     fn filter_command(self, command: CommandMeta) -> Self;
+
+    /// Like [`HandlerExt::filter_command`], then reports the match (see
+    /// [`matched`]), applies the command's timeout and runs the rest of the handler
+    /// through the middlewares of its module. Chain it right before the endpoint.
+    #[must_use]
+    fn filter_scoped_command(self, command: CommandMeta) -> Self
+    where
+        Output: From<GenericResult> + Into<GenericResult>;
 }
 
+/// Matches messages invoking `command` and injects a [`CommandInput`].
+///
+/// Needs a [`Message`] and the [`TextEdit`] the swarm injects. Edited messages match
+/// when the command is idempotent and the edit changed the text.
 #[must_use]
 pub fn filter_command<Output>(command: CommandMeta) -> Handler<'static, DependencyMap, Output>
 where
     Output: Send + Sync + 'static,
 {
-    dptree::filter_map(move |message: Message| {
-        if let Some(caps) = command.regex.captures(message.text()) {
+    dptree::filter_map(move |message: Message, edit: TextEdit| {
+        if message.edit_date().is_some() && !command.idempotent {
+            return None;
+        }
+        if edit == TextEdit::Unchanged {
+            return None;
+        }
+
+        let raw = message.text();
+        let text = if command.normalize {
+            normalize(raw)
+        } else {
+            raw.to_string()
+        };
+
+        if let Some(caps) = command.regex.captures(&text) {
             let prefix = caps.name("prefix")?.as_str().to_string();
             let cmd = caps.name("cmd")?.as_str().to_string();
 
@@ -36,6 +91,7 @@ where
                 .collect();
 
             Some(CommandInput {
+                raw: raw.to_string(),
                 prefix,
                 cmd,
                 input,
@@ -48,6 +104,133 @@ where
     })
 }
 
+/// Matches raw updates of the constructor `T` (e.g. [`tl::types::UpdateBotMessageReaction`])
+/// and injects it.
+///
+/// Peers it mentions can be resolved through the injected [`RawChats`](crate::raw::RawChats)
+/// when the client reads raw updates.
+#[must_use]
+pub fn filter_raw<T, Output>() -> Handler<'static, DependencyMap, Output>
+where
+    T: TryFrom<tl::enums::Update> + Send + Sync + 'static,
+    Output: Send + Sync + 'static,
+{
+    dptree::filter_map(|update: Update| match update {
+        Update::Raw(raw) => T::try_from(raw).ok(),
+        _ => None,
+    })
+}
+
+/// Matches albums aggregated by [`SwarmObject::media_groups`](crate::swarm::SwarmObject::media_groups)
+/// and injects the [`MediaGroup`].
+#[must_use]
+pub fn filter_media_group<Output>() -> Handler<'static, DependencyMap, Output>
+where
+    Output: Send + Sync + 'static,
+{
+    dptree::filter_map(|group: Option<MediaGroup>| group)
+}
+
+/// Matches callback queries whose data was built by [`CallbackData::encode`] under
+/// `namespace`, and injects the decoded `T`.
+#[must_use]
+pub fn filter_callback<T, Output>(
+    namespace: &'static str,
+) -> Handler<'static, DependencyMap, Output>
+where
+    T: DeserializeOwned + Send + Sync + 'static,
+    Output: Send + Sync + 'static,
+{
+    dptree::filter_map_async(move |query: CallbackQuery| async move {
+        match CallbackData::decode::<T>(namespace, query.data()).await {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("Could not decode `{}` callback data: {}", namespace, err);
+                None
+            }
+        }
+    })
+}
+
+async fn fetch_permissions(client: &Client, chat: &Chat, user: &Chat) -> Option<ChatPermissions> {
+    match ChatPermissions::fetch(client, chat, user).await {
+        Ok(permissions) => Some(permissions),
+        Err(err) => {
+            warn!("Could not fetch permissions in chat {}: {}", chat.id(), err);
+            None
+        }
+    }
+}
+
+/// Matches messages whose sender is an admin holding all of `rights`, and injects their
+/// [`ChatPermissions`].
+///
+/// Anonymous admins posting on behalf of the group only match when `rights` is empty,
+/// since Telegram does not reveal which rights they hold; see
+/// [`filter_sender_admin_or_anonymous`] to trust them anyway.
+#[must_use]
+pub fn filter_sender_admin<Output>(
+    rights: &'static [AdminRight],
+) -> Handler<'static, DependencyMap, Output>
+where
+    Output: Send + Sync + 'static,
+{
+    sender_admin(rights, ChatPermissions::anonymous_admin)
+}
+
+/// Like [`filter_sender_admin`], but anonymous admins are assumed to hold every right.
+#[must_use]
+pub fn filter_sender_admin_or_anonymous<Output>(
+    rights: &'static [AdminRight],
+) -> Handler<'static, DependencyMap, Output>
+where
+    Output: Send + Sync + 'static,
+{
+    sender_admin(rights, ChatPermissions::trusted_anonymous_admin)
+}
+
+fn sender_admin<Output>(
+    rights: &'static [AdminRight],
+    anonymous: fn() -> ChatPermissions,
+) -> Handler<'static, DependencyMap, Output>
+where
+    Output: Send + Sync + 'static,
+{
+    dptree::filter_map_async(move |message: Message, client: Arc<Client>| async move {
+        let chat = message.chat();
+        if matches!(chat, Chat::User(_)) {
+            return None;
+        }
+        let permissions = match message.sender() {
+            Some(sender) if sender.id() == chat.id() => anonymous(),
+            Some(sender @ Chat::User(_)) => fetch_permissions(&client, &chat, &sender).await?,
+            _ => return None,
+        };
+        (permissions.is_admin && permissions.has_all(rights)).then_some(permissions)
+    })
+}
+
+/// Matches messages in chats where the bot itself holds all of `rights`, and injects the
+/// bot's [`ChatPermissions`].
+#[must_use]
+pub fn filter_bot_can<Output>(
+    rights: &'static [AdminRight],
+) -> Handler<'static, DependencyMap, Output>
+where
+    Output: Send + Sync + 'static,
+{
+    dptree::filter_map_async(
+        move |message: Message, client: Arc<Client>, me: Me| async move {
+            let chat = message.chat();
+            if matches!(chat, Chat::User(_)) {
+                return None;
+            }
+            let permissions = fetch_permissions(&client, &chat, &Chat::User(me.0)).await?;
+            permissions.has_all(rights).then_some(permissions)
+        },
+    )
+}
+
 impl<Output> HandlerExt<Output> for Handler<'static, DependencyMap, Output>
 where
     Output: Send + Sync + 'static,
@@ -55,6 +238,114 @@ where
     fn filter_command(self, command: CommandMeta) -> Self {
         self.chain(filter_command::<Output>(command))
     }
+
+    fn filter_scoped_command(self, command: CommandMeta) -> Self
+    where
+        Output: From<GenericResult> + Into<GenericResult>,
+    {
+        let module = command.module;
+        let name = command.cmds.first().copied().unwrap_or_default();
+        let timeout = command.timeout;
+        self.chain(filter_command::<Output>(command))
+            .chain(matched::<Output>())
+            .chain(command_timeout::<Output>(name, timeout))
+            .chain(module_scope::<Output>(module))
+    }
+}
+
+/// Run `handler` as a predicate.
+///
+/// Returns the dependencies it passed on (including anything a `filter_map` injected), or
+/// `None` if it did not match.
+async fn probe<Output>(
+    handler: Handler<'static, DependencyMap, Output>,
+    deps: DependencyMap,
+) -> ControlFlow<Output, Option<DependencyMap>>
+where
+    Output: Send + Sync + 'static,
+{
+    let passed = Arc::new(Mutex::new(None));
+    let slot = passed.clone();
+    let result = handler
+        .execute(deps, move |deps: DependencyMap| async move {
+            *slot.lock().unwrap() = Some(deps.clone());
+            ControlFlow::Continue(deps)
+        })
+        .await;
+
+    match result {
+        ControlFlow::Break(out) => ControlFlow::Break(out),
+        ControlFlow::Continue(_) => ControlFlow::Continue(passed.lock().unwrap().take()),
+    }
+}
+
+/// Passes if any of `handlers` passes, keeping the values injected by the first that does.
+#[must_use]
+pub fn any_of<Output>(
+    handlers: impl IntoIterator<Item = Handler<'static, DependencyMap, Output>>,
+) -> Handler<'static, DependencyMap, Output>
+where
+    Output: Send + Sync + 'static,
+{
+    let handlers: Arc<[_]> = handlers.into_iter().collect();
+    dptree::from_fn(move |deps: DependencyMap, cont| {
+        let handlers = handlers.clone();
+        async move {
+            for handler in handlers.iter() {
+                match probe(handler.clone(), deps.clone()).await {
+                    ControlFlow::Break(out) => return ControlFlow::Break(out),
+                    ControlFlow::Continue(Some(passed)) => return cont(passed).await,
+                    ControlFlow::Continue(None) => {}
+                }
+            }
+            ControlFlow::Continue(deps)
+        }
+    })
+}
+
+/// Passes if all of `handlers` pass, keeping the values injected by each of them.
+#[must_use]
+pub fn all_of<Output>(
+    handlers: impl IntoIterator<Item = Handler<'static, DependencyMap, Output>>,
+) -> Handler<'static, DependencyMap, Output>
+where
+    Output: Send + Sync + 'static,
+{
+    let handlers: Arc<[_]> = handlers.into_iter().collect();
+    dptree::from_fn(move |deps: DependencyMap, cont| {
+        let handlers = handlers.clone();
+        async move {
+            let mut passed = deps.clone();
+            for handler in handlers.iter() {
+                match probe(handler.clone(), passed).await {
+                    ControlFlow::Break(out) => return ControlFlow::Break(out),
+                    ControlFlow::Continue(Some(next)) => passed = next,
+                    ControlFlow::Continue(None) => return ControlFlow::Continue(deps),
+                }
+            }
+            cont(passed).await
+        }
+    })
+}
+
+/// Passes if `handler` does not. Nothing is injected.
+#[must_use]
+pub fn not<Output>(
+    handler: Handler<'static, DependencyMap, Output>,
+) -> Handler<'static, DependencyMap, Output>
+where
+    Output: Send + Sync + 'static,
+{
+    dptree::from_fn(move |deps: DependencyMap, cont| {
+        let handler = handler.clone();
+        async move {
+            match probe(handler, deps.clone()).await {
+                ControlFlow::Break(out) => ControlFlow::Break(out),
+                ControlFlow::Continue(Some(_)) => ControlFlow::Continue(deps),
+                ControlFlow::Continue(None) => cont(deps).await,
+            }
+        }
+    })
 }
 
 mod private {
@@ -67,54 +358,56 @@ mod private {
 }
 
 macro_rules! define_ext {
-    ($ext_name:ident, $for_ty:ty => $( ($func:ident, $proj_fn:expr, $fn_doc:expr $(, $map:ident)? ) ,)*) => {
+    ($ext_name:ident, $for_ty:ty => $( ($func:ident $( ($($arg:ident: $arg_ty:ty),*) )? $([$prep:stmt])?, $proj_fn:expr, $fn_doc:expr $(, $map:ident)? ) ,)*) => {
         #[doc = concat!("Filter methods for [`", stringify!($for_ty), "`].")]
         pub trait $ext_name<Out>: private::Sealed {
-            $( define_ext!(@sig $func, $fn_doc); )*
+            $( define_ext!(@sig $func ($( $($arg: $arg_ty),* )?), $fn_doc); )*
         }
 
         impl<Out> $ext_name<Out> for $for_ty
         where
             Out: Send + Sync + 'static,
         {
-            $( define_ext!(@impl $for_ty, $func, $proj_fn $(, $map )? ); )*
+            $( define_ext!(@impl $for_ty, $func ($( $($arg: $arg_ty),* )?) $([$prep])?, $proj_fn $(, $map )? ); )*
         }
     };
 
-    (@sig $func:ident, $fn_doc:expr) => {
+    (@sig $func:ident ($($arg:ident: $arg_ty:ty),*), $fn_doc:expr) => {
         #[doc = $fn_doc]
-        fn $func() -> Handler<'static, DependencyMap, Out>;
+        fn $func($($arg: $arg_ty),*) -> Handler<'static, DependencyMap, Out>;
     };
 
-    (@impl $for_ty:ty, $func:ident, $proj_fn:expr, $map:ident) => {
-        fn $func() -> Handler<'static, DependencyMap, Out> {
+    (@impl $for_ty:ty, $func:ident ($($arg:ident: $arg_ty:ty),*) $([$prep:stmt])?, $proj_fn:expr, $map:ident) => {
+        fn $func($($arg: $arg_ty),*) -> Handler<'static, DependencyMap, Out> {
+            $($prep;)?
             dptree::filter_map(move |input: $for_ty| {
-                $proj_fn(input)
+                $proj_fn(input $(, &$arg)*)
             })
         }
     };
 
-    (@impl $for_ty:ty, $func:ident, $proj_fn:expr) => {
-        fn $func() -> Handler<'static, DependencyMap, Out> {
+    (@impl $for_ty:ty, $func:ident ($($arg:ident: $arg_ty:ty),*) $([$prep:stmt])?, $proj_fn:expr) => {
+        fn $func($($arg: $arg_ty),*) -> Handler<'static, DependencyMap, Out> {
+            $($prep;)?
             dptree::filter(move |input: $for_ty| {
-                $proj_fn(input)
+                $proj_fn(input $(, &$arg)*)
             })
         }
     };
 }
 
 macro_rules! define_update_ext {
-    ($( ($func:ident, $kind:path) ,)*) => {
+    ($( ($func:ident, $($kind:path)|+) ,)*) => {
         define_ext! {
             UpdateFilterExt, Update =>
             $(
                 (
                     $func,
                     |update: Update| match update {
-                        $kind(x) => Some(x),
+                        $($kind(x))|+ => Some(x),
                         _ => None,
                     },
-                    concat!("Filters out [`", stringify!($kind), "`] objects."),
+                    concat!("Filters out ", $("[`", stringify!($kind), "`] ",)+ "objects."),
                     map
                 ),
             )*
@@ -125,6 +418,7 @@ macro_rules! define_update_ext {
 define_update_ext! {
     (filter_new_message, Update::NewMessage),
     (filter_edited_message, Update::MessageEdited),
+    (filter_new_or_edited_message, Update::NewMessage | Update::MessageEdited),
     (filter_deleted_message, Update::MessageDeleted),
     (filter_callback_query, Update::CallbackQuery),
     (filter_inline_query, Update::InlineQuery),
@@ -133,14 +427,15 @@ define_update_ext! {
 }
 
 macro_rules! define_message_ext {
-    ($( ($func:ident, $body:expr),)*) => {
+    ($( $(#[doc = $doc:literal])* ($func:ident $( ($($arg:ident: $arg_ty:ty),*) )? $([$prep:stmt])?, $body:expr $(, $map:ident)?),)*) => {
         define_ext! {
             MessageFilterExt, Message =>
             $(
                 (
-                    $func,
+                    $func $( ($($arg: $arg_ty),*) )? $([$prep])?,
                     $body,
-                    concat!("Applies the [`", stringify!($func), "`] filter.")
+                    concat!("Applies the [`", stringify!($func), "`] filter." $(, "\n\n", $doc)*)
+                    $(, $map)?
                 ),
             )*
         }
@@ -176,4 +471,420 @@ define_message_ext! {
     (filter_forwarded, |message: Message| message.forward_count().unwrap_or(0) > 0),
     (filter_has_replies, |message: Message| message.reply_count().unwrap_or(0) > 0),
     (filter_via_bot, |message: Message| message.via_bot_id().is_some()),
+    (filter_private, |message: Message| matches!(message.chat(), Chat::User(_))),
+    /// Matches basic groups as well as supergroups.
+    (filter_group, |message: Message| matches!(message.chat(), Chat::Group(_))),
+    (filter_supergroup, |message: Message| {
+        matches!(message.chat(), Chat::Group(group) if group.is_megagroup())
+    }),
+    (filter_channel, |message: Message| matches!(message.chat(), Chat::Channel(_))),
+    (filter_chat_id(ids: IdSet), |message: Message, ids: &IdSet| {
+        ids.contains(message.chat().id())
+    }),
+    (filter_sender_id(ids: IdSet), |message: Message, ids: &IdSet| {
+        message.sender().is_some_and(|sender| ids.contains(sender.id()))
+    }),
+    (filter_sender_is_bot, |message: Message| {
+        matches!(message.sender(), Some(Chat::User(user)) if user.is_bot())
+    }),
+    /// Messages outside any topic belong to the "General" topic, whose id is `1`.
+    (filter_forum_topic(topic_id: i32), |message: Message, topic_id: &i32| {
+        forum_topic_id(&message) == *topic_id
+    }),
+    /// Injects a [`TextMatch`] with the whole match and the capture groups.
+    ///
+    /// Panics if `regex` cannot be rebuilt case-insensitively for `ignore_case`.
+    (filter_regex(regex: Regex, options: TextOptions) [let regex = options.regex(regex)], match_regex, map),
+    /// Matches when any of `keywords` appears as a whole word. Injects a [`TextMatch`].
+    (filter_keywords(keywords: Vec<String>, options: TextOptions), match_keywords, map),
+    /// Matches when the text starts with any of `prefixes`. Injects a [`TextMatch`].
+    (filter_starts_with(prefixes: Vec<String>, options: TextOptions), match_prefixes, map),
+    /// Matches when the text contains any of `needles`. Injects a [`TextMatch`].
+    (filter_contains_any(needles: Vec<String>, options: TextOptions), match_needles, map),
+    /// Matches documents whose MIME type matches `mime_glob` (e.g. `image/*`). Injects the [`Document`].
+    (filter_document(mime_glob: &'static str), |message: Message, mime_glob: &&'static str| {
+        match message.media()? {
+            Media::Document(doc) if glob_match(mime_glob, doc.mime_type().unwrap_or_default()) => Some(doc),
+            _ => None,
+        }
+    }, map),
+    (filter_sticker, |message: Message| match message.media()? {
+        Media::Sticker(sticker) => Some(sticker),
+        _ => None,
+    }, map),
+    (filter_voice, |message: Message| document_of_kind(&message, DocumentKind::Voice).map(Voice), map),
+    (filter_audio, |message: Message| document_of_kind(&message, DocumentKind::Audio).map(Audio), map),
+    (filter_video, |message: Message| document_of_kind(&message, DocumentKind::Video).map(Video), map),
+    (filter_video_note, |message: Message| {
+        document_of_kind(&message, DocumentKind::VideoNote).map(VideoNote)
+    }, map),
+    (filter_animation, |message: Message| {
+        document_of_kind(&message, DocumentKind::Animation).map(Animation)
+    }, map),
+    (filter_contact, |message: Message| match message.media()? {
+        Media::Contact(contact) => Some(contact),
+        _ => None,
+    }, map),
+    (filter_location, |message: Message| match message.media()? {
+        Media::Geo(geo) => Some(geo),
+        _ => None,
+    }, map),
+    (filter_poll, |message: Message| match message.media()? {
+        Media::Poll(poll) => Some(poll),
+        _ => None,
+    }, map),
+    (filter_dice, |message: Message| match message.media()? {
+        Media::Dice(dice) => Some(dice),
+        _ => None,
+    }, map),
+    /// Matches documents whose size in bytes lies in `range`.
+    (filter_file_size(range: impl RangeBounds<i64> + Send + Sync + 'static), in_size_range),
+    /// Matches audio and video documents whose duration in seconds lies in `range`.
+    (filter_duration(range: impl RangeBounds<f64> + Send + Sync + 'static), in_duration_range),
+    /// Injects a [`MemberJoined`].
+    (filter_member_joined, |message: Message| MemberJoined::from_message(&message), map),
+    /// Injects a [`MemberLeft`].
+    (filter_member_left, |message: Message| MemberLeft::from_message(&message), map),
+    /// Injects a [`ChatTitleChanged`].
+    (filter_chat_title_changed, |message: Message| ChatTitleChanged::from_message(&message), map),
+    /// Injects a [`ChatPhotoChanged`].
+    (filter_chat_photo_changed, |message: Message| ChatPhotoChanged::from_message(&message), map),
+    /// Injects a [`PinnedMessage`].
+    (filter_pinned_message, |message: Message| PinnedMessage::from_message(&message), map),
+    /// Injects a [`ChatMigrated`].
+    (filter_chat_migrated, |message: Message| ChatMigrated::from_message(&message), map),
+    /// Injects a [`GroupCallEvent`].
+    (filter_group_call, |message: Message| GroupCallEvent::from_message(&message), map),
+    /// Injects a [`ChatCreated`].
+    (filter_chat_created, |message: Message| ChatCreated::from_message(&message), map),
+}
+
+fn document_of_kind(message: &Message, kind: DocumentKind) -> Option<Document> {
+    match message.media()? {
+        Media::Document(doc) if document_kind(&doc) == kind => Some(doc),
+        _ => None,
+    }
+}
+
+fn in_size_range<R: RangeBounds<i64>>(message: Message, range: &R) -> bool {
+    match message.media() {
+        Some(Media::Document(doc)) => document_size(&doc).is_some_and(|size| range.contains(&size)),
+        _ => false,
+    }
+}
+
+fn in_duration_range<R: RangeBounds<f64>>(message: Message, range: &R) -> bool {
+    match message.media() {
+        Some(Media::Document(doc)) => {
+            document_duration(&doc).is_some_and(|duration| range.contains(&duration))
+        }
+        _ => false,
+    }
+}
+
+fn match_regex(message: Message, regex: &Regex, options: &TextOptions) -> Option<TextMatch> {
+    regex_match(&options.apply(message.text()), regex)
+}
+
+fn regex_match(text: &str, regex: &Regex) -> Option<TextMatch> {
+    let caps = regex.captures(text)?;
+    Some(TextMatch {
+        matched: caps.get(0)?.as_str().to_string(),
+        keyword: None,
+        groups: caps
+            .iter()
+            .map(|m| m.map(|m| m.as_str().to_string()))
+            .collect(),
+        captures: regex
+            .capture_names()
+            .flatten()
+            .filter_map(|name| Some((name.to_string(), caps.name(name)?.as_str().to_string())))
+            .collect(),
+    })
+}
+
+fn match_keywords(
+    message: Message,
+    keywords: &[String],
+    options: &TextOptions,
+) -> Option<TextMatch> {
+    keyword_match(&options.apply(message.text()), keywords, options)
+}
+
+fn keyword_match(text: &str, keywords: &[String], options: &TextOptions) -> Option<TextMatch> {
+    keywords.iter().find_map(|keyword| {
+        let keyword = options.apply(keyword);
+        let range = occurrences(text, &keyword, options.ignore_case)
+            .find(|range| is_word(text, range.clone()))?;
+        Some(TextMatch::keyword(text, range, keyword))
+    })
+}
+
+fn match_prefixes(
+    message: Message,
+    prefixes: &[String],
+    options: &TextOptions,
+) -> Option<TextMatch> {
+    prefix_match(&options.apply(message.text()), prefixes, options)
+}
+
+fn prefix_match(text: &str, prefixes: &[String], options: &TextOptions) -> Option<TextMatch> {
+    prefixes.iter().find_map(|prefix| {
+        let prefix = options.apply(prefix);
+        let end = match_at(text, 0, &prefix, options.ignore_case)?;
+        Some(TextMatch::keyword(text, 0..end, prefix))
+    })
+}
+
+fn match_needles(message: Message, needles: &[String], options: &TextOptions) -> Option<TextMatch> {
+    needle_match(&options.apply(message.text()), needles, options)
+}
+
+fn needle_match(text: &str, needles: &[String], options: &TextOptions) -> Option<TextMatch> {
+    needles.iter().find_map(|needle| {
+        let needle = options.apply(needle);
+        let range = occurrences(text, &needle, options.ignore_case).next()?;
+        Some(TextMatch::keyword(text, range, needle))
+    })
+}
+
+/// How text filters preprocess the message text before matching.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TextOptions {
+    /// Match regardless of case. The injected [`TextMatch`] still holds the text as it
+    /// was written.
+    pub ignore_case: bool,
+    /// Run the text through [`normalize`] first.
+    pub normalize: bool,
+}
+
+impl TextOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ignore_case(mut self) -> Self {
+        self.ignore_case = true;
+        self
+    }
+
+    pub fn normalize(mut self) -> Self {
+        self.normalize = true;
+        self
+    }
+
+    /// `regex` as [`MessageFilterExt::filter_regex`] matches it.
+    fn regex(&self, regex: Regex) -> Regex {
+        if !self.ignore_case {
+            return regex;
+        }
+        RegexBuilder::new(regex.as_str())
+            .case_insensitive(true)
+            .build()
+            .unwrap_or_else(|err| panic!("cannot match `{}` ignoring case: {err}", regex.as_str()))
+    }
+
+    /// The text that is matched against: `text` itself, or its normalized form.
+    pub fn apply(&self, text: &str) -> String {
+        if self.normalize {
+            normalize(text)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// Result of a successful text filter, injected into the [`DependencyMap`].
+#[derive(Debug, Clone, Default)]
+pub struct TextMatch {
+    /// The matched part of the (normalized) text, as it was written.
+    pub matched: String,
+    /// The keyword, prefix or needle that matched.
+    pub keyword: Option<String>,
+    /// Positional groups of a regex match; index `0` is the whole match.
+    pub groups: Vec<Option<String>>,
+    /// Named groups of a regex match.
+    pub captures: HashMap<String, String>,
+}
+
+impl TextMatch {
+    fn keyword(text: &str, range: Range<usize>, keyword: String) -> Self {
+        Self {
+            matched: text[range].to_string(),
+            keyword: Some(keyword),
+            ..Default::default()
+        }
+    }
+
+    /// Get a named capture group.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.captures.get(name).map(String::as_str)
+    }
+}
+
+/// Byte ranges of `text` where `needle` occurs.
+fn occurrences<'a>(
+    text: &'a str,
+    needle: &'a str,
+    ignore_case: bool,
+) -> impl Iterator<Item = Range<usize>> + 'a {
+    text.char_indices()
+        .filter_map(move |(start, _)| Some(start..match_at(text, start, needle, ignore_case)?))
+}
+
+/// End of `needle` if it occurs in `text` at byte offset `start`.
+///
+/// Caseless comparison goes char by char, so the end is an offset into `text` even when
+/// the lowercase forms differ in length.
+fn match_at(text: &str, start: usize, needle: &str, ignore_case: bool) -> Option<usize> {
+    if needle.is_empty() {
+        return None;
+    }
+    let rest = &text[start..];
+    if !ignore_case {
+        return rest.starts_with(needle).then(|| start + needle.len());
+    }
+    let mut chars = rest.char_indices();
+    for expected in needle.chars() {
+        let (_, actual) = chars.next()?;
+        if !actual.to_lowercase().eq(expected.to_lowercase()) {
+            return None;
+        }
+    }
+    Some(start + chars.next().map_or(rest.len(), |(end, _)| end))
+}
+
+fn is_word(text: &str, range: Range<usize>) -> bool {
+    let before = text[..range.start].chars().next_back();
+    let after = text[range.end..].chars().next();
+    !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+}
+
+/// Id of the forum topic `message` was posted in.
+pub fn forum_topic_id(message: &Message) -> i32 {
+    match message.reply_header() {
+        Some(tl::enums::MessageReplyHeader::Header(header)) if header.forum_topic => header
+            .reply_to_top_id
+            .or(header.reply_to_msg_id)
+            .unwrap_or(GENERAL_TOPIC_ID),
+        _ => GENERAL_TOPIC_ID,
+    }
+}
+
+const GENERAL_TOPIC_ID: i32 = 1;
+
+/// A set of chat or user ids shared between a filter and the rest of the bot.
+///
+/// Clones share the same set, so ids added or removed through any handle are seen by
+/// filters like [`MessageFilterExt::filter_chat_id`] without rebuilding the handler tree.
+#[derive(Debug, Clone, Default)]
+pub struct IdSet(Arc<RwLock<HashSet<i64>>>);
+
+impl IdSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, id: i64) -> bool {
+        self.0.read().unwrap().contains(&id)
+    }
+
+    pub fn insert(&self, id: i64) -> bool {
+        self.0.write().unwrap().insert(id)
+    }
+
+    pub fn remove(&self, id: i64) -> bool {
+        self.0.write().unwrap().remove(&id)
+    }
+
+    /// Replace the whole set at once.
+    pub fn replace<I: IntoIterator<Item = i64>>(&self, ids: I) {
+        *self.0.write().unwrap() = ids.into_iter().collect();
+    }
+}
+
+impl FromIterator<i64> for IdSet {
+    fn from_iter<I: IntoIterator<Item = i64>>(iter: I) -> Self {
+        Self(Arc::new(RwLock::new(iter.into_iter().collect())))
+    }
+}
+
+impl<const N: usize> From<[i64; N]> for IdSet {
+    fn from(ids: [i64; N]) -> Self {
+        ids.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_filter_raw() {
+        let handler = filter_raw::<tl::types::UpdateDcOptions, usize>().endpoint(
+            |options: tl::types::UpdateDcOptions| async move { options.dc_options.len() },
+        );
+
+        let dc_options = Update::Raw(
+            tl::types::UpdateDcOptions {
+                dc_options: Vec::new(),
+            }
+            .into(),
+        );
+        let other = Update::Raw(
+            tl::types::UpdateChatParticipantDelete {
+                chat_id: 1,
+                user_id: 2,
+                version: 1,
+            }
+            .into(),
+        );
+        assert_eq!(
+            handler.dispatch(dptree::deps![dc_options]).await,
+            ControlFlow::Break(0)
+        );
+        assert!(handler.dispatch(dptree::deps![other]).await.is_continue());
+    }
+
+    #[test]
+    fn test_regex_ignore_case() {
+        let regex = Regex::new(r"Hello (?<name>\w+)").unwrap();
+        let caseless = TextOptions::new().ignore_case().regex(regex.clone());
+        let matched = regex_match("HELLO World", &caseless).unwrap();
+        assert_eq!(matched.matched, "HELLO World");
+        assert_eq!(matched.get("name"), Some("World"));
+        assert!(regex_match("HELLO World", &TextOptions::new().regex(regex)).is_none());
+    }
+
+    #[test]
+    fn test_keywords_return_original_text() {
+        let options = TextOptions::new().ignore_case();
+        let matched = keyword_match("Say HeLLo there", &words(&["hello"]), &options).unwrap();
+        assert_eq!(matched.matched, "HeLLo");
+        assert_eq!(matched.keyword.as_deref(), Some("hello"));
+        assert!(
+            keyword_match("Say HeLLo there", &words(&["hello"]), &TextOptions::new()).is_none()
+        );
+    }
+
+    #[test]
+    fn test_keywords_whole_words() {
+        let options = TextOptions::new().ignore_case();
+        assert!(keyword_match("othello", &words(&["hello"]), &options).is_none());
+        assert!(keyword_match("hello, world", &words(&["hello"]), &options).is_some());
+    }
+
+    #[test]
+    fn test_prefixes_and_needles() {
+        let options = TextOptions::new().ignore_case();
+        let matched = prefix_match("ÄBC def", &words(&["äb"]), &options).unwrap();
+        assert_eq!(matched.matched, "ÄB");
+        let matched = needle_match("xx ÄBC", &words(&["bc"]), &options).unwrap();
+        assert_eq!(matched.matched, "BC");
+        assert!(prefix_match("def ÄBC", &words(&["äb"]), &options).is_none());
+    }
 }
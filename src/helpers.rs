@@ -1,25 +1,26 @@
-use core::slice;
 use std::marker::PhantomData;
 
 use dialoguer::{Input, Password};
 use grammers_client::{
-    Client, Config, InitParams, InvocationError, SignInError, grammers_tl_types as tl,
-    session::Session, types::Message,
+    Client, Config, InitParams, InvocationError, SignInError, session::Session, types::Message,
 };
 
+use crate::cache::UpdateCache;
+
 pub async fn get_reply(
     client: Client,
     message: Message,
 ) -> Result<Option<Message>, InvocationError> {
-    if let Some(tl::enums::MessageReplyHeader::Header(reply_header)) = message.reply_header() {
-        if let Some(reply_msg_id) = reply_header.reply_to_msg_id {
-            return client
-                .get_messages_by_id(message.chat(), slice::from_ref(&reply_msg_id))
-                .await
-                .map(|msgs| msgs.into_iter().flatten().next());
-        }
-    }
-    Ok(None)
+    UpdateCache::new().get_reply(&client, &message).await
+}
+
+/// Like [`get_reply`], memoized through the update's [`UpdateCache`].
+pub async fn get_reply_cached(
+    client: Client,
+    message: Message,
+    cache: UpdateCache,
+) -> Result<Option<Message>, InvocationError> {
+    cache.get_reply(&client, &message).await
 }
 
 /// Phantom‐types for mode
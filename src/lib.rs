@@ -1,25 +1,52 @@
 use std::sync::Arc;
 
 pub use gramhive_macros::*;
+pub mod album;
+pub mod cache;
+pub mod callback;
 pub mod commands;
+pub mod concurrency;
 pub mod errors;
 pub mod event;
 pub mod extractors;
 pub mod filters;
 pub mod helpers;
+pub mod media;
+pub mod middleware;
+pub mod normalize;
+pub mod panic;
+pub mod permissions;
+pub mod raw;
+pub mod replies;
+pub mod retry;
 pub mod router;
+pub mod scope;
+pub mod sequence;
+pub mod service;
 pub mod swarm;
 pub mod tests;
 pub mod tg_html;
+mod timeout;
 
+pub use cache::UpdateCache;
+pub use callback::CallbackData;
 pub use errors::ArgumentError;
+pub use errors::Error;
 pub use event::Event;
 pub use event::EventListener;
 pub use helpers::ClientBuilder;
 pub use helpers::get_reply;
-pub use swarm::Swarm;
+pub use helpers::get_reply_cached;
+pub use middleware::{Middleware, Next};
+pub use router::{
+    DispatchOutcome, DispatchStrategy, HandlerPath, Router, RouterHandle, RouterToggle,
+};
+pub use swarm::{Swarm, SwarmStats};
 pub use tg_html::TgHtml;
 pub use tg_html::tg_html;
 
-pub type GenericResult = Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>;
-pub type ArcBoxedError = Arc<Box<dyn std::error::Error + Send + Sync + 'static>>;
+pub type GenericResult = Result<(), Error>;
+/// A handler error as shared with event listeners and error handlers.
+pub type SharedError = Arc<Error>;
+#[deprecated(note = "renamed to `SharedError`, it no longer holds a `Box`")]
+pub type ArcBoxedError = SharedError;
@@ -0,0 +1,183 @@
+use std::ops::Deref;
+
+use grammers_client::{grammers_tl_types as tl, types::media::Document};
+
+macro_rules! define_document_kind {
+    ($( $(#[doc = $doc:literal])* $name:ident ,)*) => {
+        $(
+            $(#[doc = $doc])*
+            #[derive(Debug, Clone)]
+            pub struct $name(pub Document);
+
+            impl Deref for $name {
+                type Target = Document;
+
+                fn deref(&self) -> &Self::Target {
+                    &self.0
+                }
+            }
+        )*
+    };
+}
+
+define_document_kind! {
+    /// A voice message.
+    Voice,
+    /// An audio file, such as a song.
+    Audio,
+    /// A video that is neither a GIF nor a video note.
+    Video,
+    /// A round video message.
+    VideoNote,
+    /// A GIF or a silent looping video.
+    Animation,
+}
+
+/// The kind of a [`Document`], as told by its attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentKind {
+    Voice,
+    Audio,
+    Video,
+    VideoNote,
+    Animation,
+    Sticker,
+    File,
+}
+
+fn raw_document(document: &Document) -> Option<&tl::types::Document> {
+    match document.raw.document.as_ref()? {
+        tl::enums::Document::Document(doc) => Some(doc),
+        tl::enums::Document::Empty(_) => None,
+    }
+}
+
+fn attributes(document: &Document) -> &[tl::enums::DocumentAttribute] {
+    raw_document(document).map_or(&[], |doc| doc.attributes.as_slice())
+}
+
+pub fn document_kind(document: &Document) -> DocumentKind {
+    attributes_kind(attributes(document))
+}
+
+fn attributes_kind(attrs: &[tl::enums::DocumentAttribute]) -> DocumentKind {
+    use tl::enums::DocumentAttribute as A;
+
+    if attrs.iter().any(|a| matches!(a, A::Sticker(_))) {
+        return DocumentKind::Sticker;
+    }
+    if attrs.iter().any(|a| matches!(a, A::Animated)) {
+        return DocumentKind::Animation;
+    }
+    for attr in attrs {
+        match attr {
+            A::Audio(audio) if audio.voice => return DocumentKind::Voice,
+            A::Audio(_) => return DocumentKind::Audio,
+            A::Video(video) if video.round_message => return DocumentKind::VideoNote,
+            A::Video(_) => return DocumentKind::Video,
+            _ => {}
+        }
+    }
+    DocumentKind::File
+}
+
+/// Size of the document in bytes.
+pub fn document_size(document: &Document) -> Option<i64> {
+    raw_document(document).map(|doc| doc.size)
+}
+
+/// Duration of an audio or video document in seconds.
+pub fn document_duration(document: &Document) -> Option<f64> {
+    use tl::enums::DocumentAttribute as A;
+
+    attributes(document).iter().find_map(|attr| match attr {
+        A::Audio(audio) => Some(audio.duration as f64),
+        A::Video(video) => Some(video.duration),
+        _ => None,
+    })
+}
+
+/// Match `text` against a glob where `*` stands for any run of characters,
+/// e.g. `image/*` or `application/*pdf`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(round_message: bool) -> tl::enums::DocumentAttribute {
+        tl::types::DocumentAttributeVideo {
+            round_message,
+            supports_streaming: false,
+            nosound: false,
+            duration: 1.0,
+            w: 240,
+            h: 240,
+            preload_prefix_size: None,
+            video_start_ts: None,
+            video_codec: None,
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_glob_star_alone() {
+        assert!(glob_match("*", "image/png"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn test_glob_prefix() {
+        assert!(glob_match("image/*", "image/png"));
+        assert!(glob_match("image/*", "image/"));
+        assert!(!glob_match("image/*", "video/mp4"));
+    }
+
+    #[test]
+    fn test_glob_suffix() {
+        assert!(glob_match("*pdf", "application/pdf"));
+        assert!(glob_match("application/*pdf", "application/x-pdf"));
+        assert!(!glob_match("*pdf", "application/pdf+zip"));
+    }
+
+    #[test]
+    fn test_glob_no_match() {
+        assert!(!glob_match("image/png", "image/jpeg"));
+        assert!(!glob_match("audio/*", "application/ogg"));
+        assert!(!glob_match("a*b*c", "acb"));
+    }
+
+    #[test]
+    fn test_document_kind_precedence() {
+        let animated = tl::enums::DocumentAttribute::Animated;
+        assert_eq!(
+            attributes_kind(&[video(false), animated.clone()]),
+            DocumentKind::Animation
+        );
+        assert_eq!(
+            attributes_kind(&[video(true), animated]),
+            DocumentKind::Animation
+        );
+        assert_eq!(attributes_kind(&[video(true)]), DocumentKind::VideoNote);
+        assert_eq!(attributes_kind(&[video(false)]), DocumentKind::Video);
+        assert_eq!(attributes_kind(&[]), DocumentKind::File);
+    }
+}
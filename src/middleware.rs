@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    ops::ControlFlow,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use dptree::{Handler, di::DependencyMap};
+use futures::future::BoxFuture;
+
+use crate::GenericResult;
+
+/// Logic wrapped around handler execution.
+///
+/// A middleware can inspect or extend `deps` before calling `next`, skip what it wraps
+/// entirely by not calling it, and inspect or replace the result afterwards.
+///
+/// Router-wide middlewares wrap all handlers of a router and run once per dispatch.
+/// There `next` returns [`Error::Handled`](crate::Error::Handled) if any handler failed,
+/// and `Ok(())` otherwise, including when none matched. Module middlewares wrap a
+/// command's endpoint and see its result.
+#[async_trait]
+pub trait Middleware: Send + Sync + 'static {
+    async fn call(&self, deps: DependencyMap, next: Next) -> GenericResult;
+}
+
+pub(crate) type Endpoint =
+    Box<dyn FnOnce(DependencyMap) -> BoxFuture<'static, GenericResult> + Send>;
+
+/// The rest of the middleware chain, ending in the handler itself.
+pub struct Next {
+    middlewares: Vec<Arc<dyn Middleware>>,
+    index: usize,
+    endpoint: Endpoint,
+}
+
+impl Next {
+    pub(crate) fn new(middlewares: Vec<Arc<dyn Middleware>>, endpoint: Endpoint) -> Self {
+        Self {
+            middlewares,
+            index: 0,
+            endpoint,
+        }
+    }
+
+    pub async fn run(mut self, deps: DependencyMap) -> GenericResult {
+        match self.middlewares.get(self.index).cloned() {
+            Some(middleware) => {
+                self.index += 1;
+                middleware.call(deps, self).await
+            }
+            None => (self.endpoint)(deps).await,
+        }
+    }
+}
+
+/// Middlewares registered for command modules, looked up by [`module_scope`].
+#[derive(Clone, Default)]
+pub(crate) struct ModuleMiddlewares(pub(crate) HashMap<&'static str, Vec<Arc<dyn Middleware>>>);
+
+/// Run the rest of a command handler through the middlewares of its `module`.
+pub(crate) fn module_scope<Output>(
+    module: Option<&'static str>,
+) -> Handler<'static, DependencyMap, Output>
+where
+    Output: From<GenericResult> + Into<GenericResult> + Send + Sync + 'static,
+{
+    dptree::from_fn(move |deps: DependencyMap, cont| async move {
+        let middlewares = module.and_then(|module| {
+            let registry = deps.clone().remove::<ModuleMiddlewares>()?;
+            registry.0.get(module).cloned()
+        });
+        let Some(middlewares) = middlewares else {
+            return cont(deps).await;
+        };
+
+        let unmatched = Arc::new(Mutex::new(None));
+        let slot = unmatched.clone();
+        let endpoint: Endpoint = Box::new(move |deps| {
+            Box::pin(async move {
+                match cont(deps).await {
+                    ControlFlow::Break(output) => output.into(),
+                    ControlFlow::Continue(deps) => {
+                        *slot.lock().unwrap() = Some(deps);
+                        Ok(())
+                    }
+                }
+            })
+        });
+
+        let result = Next::new(middlewares, endpoint).run(deps).await;
+        let unmatched = unmatched.lock().unwrap().take();
+        match (result, unmatched) {
+            (Ok(()), Some(deps)) => ControlFlow::Continue(deps),
+            (result, _) => ControlFlow::Break(result.into()),
+        }
+    })
+}
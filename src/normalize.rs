@@ -0,0 +1,146 @@
+/// Code points of the zero digit of every Unicode decimal digit run (general category
+/// `Nd`, as of Unicode 16) other than ASCII. Each run holds the digits zero to nine.
+const DIGIT_ZEROS: &[u32] = &[
+    0x0660,  // Arabic-Indic
+    0x06F0,  // Extended Arabic-Indic (Persian, Urdu)
+    0x07C0,  // NKo
+    0x0966,  // Devanagari
+    0x09E6,  // Bengali
+    0x0A66,  // Gurmukhi
+    0x0AE6,  // Gujarati
+    0x0B66,  // Oriya
+    0x0BE6,  // Tamil
+    0x0C66,  // Telugu
+    0x0CE6,  // Kannada
+    0x0D66,  // Malayalam
+    0x0DE6,  // Sinhala Lith
+    0x0E50,  // Thai
+    0x0ED0,  // Lao
+    0x0F20,  // Tibetan
+    0x1040,  // Myanmar
+    0x1090,  // Myanmar Shan
+    0x17E0,  // Khmer
+    0x1810,  // Mongolian
+    0x1946,  // Limbu
+    0x19D0,  // New Tai Lue
+    0x1A80,  // Tai Tham Hora
+    0x1A90,  // Tai Tham Tham
+    0x1B50,  // Balinese
+    0x1BB0,  // Sundanese
+    0x1C40,  // Lepcha
+    0x1C50,  // Ol Chiki
+    0xA620,  // Vai
+    0xA8D0,  // Saurashtra
+    0xA900,  // Kayah Li
+    0xA9D0,  // Javanese
+    0xA9F0,  // Myanmar Tai Laing
+    0xAA50,  // Cham
+    0xABF0,  // Meetei Mayek
+    0xFF10,  // Fullwidth
+    0x104A0, // Osmanya
+    0x10D30, // Hanifi Rohingya
+    0x10D40, // Garay
+    0x11066, // Brahmi
+    0x110F0, // Sora Sompeng
+    0x11136, // Chakma
+    0x111D0, // Sharada
+    0x112F0, // Khudawadi
+    0x11450, // Newa
+    0x114D0, // Tirhuta
+    0x11650, // Modi
+    0x116C0, // Takri
+    0x116D0, // Myanmar Pao
+    0x116DA, // Myanmar Eastern Pwo Karen
+    0x11730, // Ahom
+    0x118E0, // Warang Citi
+    0x11950, // Dives Akuru
+    0x11BF0, // Sunuwar
+    0x11C50, // Bhaiksuki
+    0x11D50, // Masaram Gondi
+    0x11DA0, // Gunjala Gondi
+    0x11F50, // Kawi
+    0x16130, // Gurung Khema
+    0x16A60, // Mro
+    0x16AC0, // Tangsa
+    0x16B50, // Pahawh Hmong
+    0x16D70, // Kirat Rai
+    0x1CCF0, // Outlined
+    0x1D7CE, // Mathematical bold
+    0x1D7D8, // Mathematical double-struck
+    0x1D7E2, // Mathematical sans-serif
+    0x1D7EC, // Mathematical sans-serif bold
+    0x1D7F6, // Mathematical monospace
+    0x1E140, // Nyiakeng Puachue Hmong
+    0x1E2F0, // Wancho
+    0x1E4F0, // Nag Mundari
+    0x1E5F1, // Ol Onal
+    0x1E950, // Adlam
+    0x1FBF0, // Segmented
+];
+
+/// Map a Unicode decimal digit to its ASCII counterpart.
+pub fn normalize_digit(c: char) -> Option<char> {
+    let code = c as u32;
+    DIGIT_ZEROS
+        .iter()
+        .find(|&&zero| (zero..zero + 10).contains(&code))
+        .and_then(|zero| char::from_digit(code - zero, 10))
+}
+
+/// Normalize user input for parsing.
+///
+/// Folds Unicode decimal digits (`۱۲۳`, `١٢٣`, ...) to ASCII, unifies the Arabic
+/// and Persian forms of ye and kaf, and strips zero-width characters.
+pub fn normalize(text: &str) -> String {
+    text.chars()
+        .filter_map(|c| match c {
+            '\u{200B}' | '\u{200C}' | '\u{200D}' | '\u{2060}' | '\u{FEFF}' => None,
+            'ي' | 'ى' => Some('ی'),
+            'ك' => Some('ک'),
+            c if c.is_ascii() => Some(c),
+            c => Some(normalize_digit(c).unwrap_or(c)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persian_and_arabic_digits() {
+        assert_eq!(normalize("۱۲۳۴۵۶۷۸۹۰"), "1234567890");
+        assert_eq!(normalize("١٢٣٤٥٦٧٨٩٠"), "1234567890");
+        assert_eq!(normalize("/ban ۱۰m"), "/ban 10m");
+    }
+
+    #[test]
+    fn test_other_digits() {
+        assert_eq!(normalize_digit('५'), Some('5')); // Devanagari
+        assert_eq!(normalize_digit('７'), Some('7')); // Fullwidth
+        assert_eq!(normalize_digit('𑁩'), Some('3')); // Brahmi
+        assert_eq!(normalize_digit('𞥗'), Some('7')); // Adlam
+        assert_eq!(normalize_digit('a'), None);
+        assert_eq!(normalize_digit('٪'), None);
+    }
+
+    #[test]
+    fn test_ye_and_kaf() {
+        assert_eq!(normalize("علي"), "علی");
+        assert_eq!(normalize("مصطفى"), "مصطفی");
+        assert_eq!(normalize("كتاب"), "کتاب");
+    }
+
+    #[test]
+    fn test_zero_width_stripping() {
+        assert_eq!(normalize("می\u{200C}خواهم"), "میخواهم");
+        assert_eq!(normalize("a\u{200D}b\u{200B}c\u{FEFF}"), "abc");
+    }
+
+    #[test]
+    fn test_other_text_is_preserved() {
+        let raw = "/start Hello, World! ßüé سلام 🙂";
+        assert_eq!(normalize(raw), raw);
+        assert_eq!(normalize(""), "");
+    }
+}
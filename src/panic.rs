@@ -0,0 +1,67 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Once,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use futures::FutureExt;
+
+use crate::errors::{DispatchError, HandlerId};
+
+static PANICS: AtomicU64 = AtomicU64::new(0);
+static HOOK: Once = Once::new();
+
+thread_local! {
+    /// Where the last panic on this thread happened, recorded by the panic hook.
+    static LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Number of handler panics caught since the process started.
+pub fn count() -> u64 {
+    PANICS.load(Ordering::Relaxed)
+}
+
+/// Chain a hook that records panic locations in front of the current one.
+fn install_hook() {
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let location = info.location().map(|location| location.to_string());
+            LOCATION.with(|slot| *slot.borrow_mut() = location);
+            previous(info);
+        }));
+    });
+}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+/// Run `future`, turning a panic into [`DispatchError::Panic`].
+pub(crate) async fn catch<F>(handler: HandlerId, future: F) -> Result<F::Output, DispatchError>
+where
+    F: Future,
+{
+    install_hook();
+    AssertUnwindSafe(future)
+        .catch_unwind()
+        .await
+        .map_err(|payload| {
+            PANICS.fetch_add(1, Ordering::Relaxed);
+            DispatchError::Panic {
+                handler,
+                message: message(payload.as_ref()),
+                location: LOCATION.with(|slot| slot.borrow_mut().take()),
+            }
+        })
+}
@@ -0,0 +1,313 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use grammers_client::{
+    Client, InvocationError, grammers_tl_types as tl,
+    types::{Chat, Update},
+};
+use lru::LruCache;
+
+/// A single administrator right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AdminRight {
+    ChangeInfo,
+    PostMessages,
+    EditMessages,
+    DeleteMessages,
+    BanUsers,
+    InviteUsers,
+    PinMessages,
+    AddAdmins,
+    ManageCall,
+}
+
+impl AdminRight {
+    pub const ALL: &'static [AdminRight] = &[
+        AdminRight::ChangeInfo,
+        AdminRight::PostMessages,
+        AdminRight::EditMessages,
+        AdminRight::DeleteMessages,
+        AdminRight::BanUsers,
+        AdminRight::InviteUsers,
+        AdminRight::PinMessages,
+        AdminRight::AddAdmins,
+        AdminRight::ManageCall,
+    ];
+}
+
+/// Permissions of a participant in a chat, injected by the admin filters.
+#[derive(Debug, Clone, Default)]
+pub struct ChatPermissions {
+    pub is_creator: bool,
+    pub is_admin: bool,
+    /// The message was posted by an anonymous admin on behalf of the group.
+    ///
+    /// Telegram does not reveal which admin that was, so no specific right is known.
+    pub anonymous: bool,
+    pub rights: Vec<AdminRight>,
+}
+
+impl ChatPermissions {
+    /// An anonymous admin, holding no known right.
+    pub fn anonymous_admin() -> Self {
+        Self {
+            is_creator: false,
+            is_admin: true,
+            anonymous: true,
+            rights: Vec::new(),
+        }
+    }
+
+    /// An anonymous admin trusted with every right.
+    pub fn trusted_anonymous_admin() -> Self {
+        Self {
+            rights: AdminRight::ALL.to_vec(),
+            ..Self::anonymous_admin()
+        }
+    }
+
+    pub fn has(&self, right: AdminRight) -> bool {
+        self.rights.contains(&right)
+    }
+
+    pub fn has_all(&self, rights: &[AdminRight]) -> bool {
+        rights.iter().all(|right| self.has(*right))
+    }
+
+    /// Fetch the permissions of `user` in `chat`, going through the permission cache.
+    pub async fn fetch(
+        client: &Client,
+        chat: &Chat,
+        user: &Chat,
+    ) -> Result<ChatPermissions, InvocationError> {
+        let key = (chat.id(), user.id());
+        if let Some(permissions) = cache_get(key) {
+            return Ok(permissions);
+        }
+
+        let raw = client.get_permissions(chat.pack(), user.pack()).await?;
+        let rights = if raw.is_creator() {
+            AdminRight::ALL.to_vec()
+        } else {
+            AdminRight::ALL
+                .iter()
+                .copied()
+                .filter(|right| match right {
+                    AdminRight::ChangeInfo => raw.can_change_info(),
+                    AdminRight::PostMessages => raw.can_post_messages(),
+                    AdminRight::EditMessages => raw.can_edit_messages(),
+                    AdminRight::DeleteMessages => raw.can_delete_messages(),
+                    AdminRight::BanUsers => raw.can_ban_users(),
+                    AdminRight::InviteUsers => raw.can_invite_users(),
+                    AdminRight::PinMessages => raw.can_pin_messages(),
+                    AdminRight::AddAdmins => raw.can_add_admins(),
+                    AdminRight::ManageCall => raw.manage_call(),
+                })
+                .collect()
+        };
+        let permissions = ChatPermissions {
+            is_creator: raw.is_creator(),
+            is_admin: raw.is_admin() || raw.is_creator(),
+            anonymous: false,
+            rights,
+        };
+
+        cache_put(key, permissions.clone());
+        Ok(permissions)
+    }
+}
+
+struct PermissionCache {
+    entries: LruCache<(i64, i64), (Instant, ChatPermissions)>,
+    ttl: Duration,
+}
+
+impl PermissionCache {
+    fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        Self {
+            entries: LruCache::new(capacity),
+            ttl,
+        }
+    }
+
+    fn get(&mut self, key: (i64, i64)) -> Option<ChatPermissions> {
+        let ttl = self.ttl;
+        let fresh = self
+            .entries
+            .get(&key)
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < ttl)
+            .map(|(_, permissions)| permissions.clone());
+        if fresh.is_none() {
+            self.entries.pop(&key);
+        }
+        fresh
+    }
+
+    fn put(&mut self, key: (i64, i64), permissions: ChatPermissions) {
+        self.entries.put(key, (Instant::now(), permissions));
+    }
+
+    fn invalidate_chat(&mut self, chat_id: i64) {
+        let stale: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|(chat, _)| *chat == chat_id)
+            .collect();
+        for key in stale {
+            self.entries.pop(&key);
+        }
+    }
+}
+
+static CACHE: OnceLock<Mutex<PermissionCache>> = OnceLock::new();
+
+fn init_cache() -> &'static Mutex<PermissionCache> {
+    CACHE.get_or_init(|| {
+        Mutex::new(PermissionCache::new(
+            NonZeroUsize::new(4096).unwrap(),
+            Duration::from_secs(300),
+        ))
+    })
+}
+
+fn cache_get(key: (i64, i64)) -> Option<ChatPermissions> {
+    init_cache().lock().unwrap().get(key)
+}
+
+fn cache_put(key: (i64, i64), permissions: ChatPermissions) {
+    init_cache().lock().unwrap().put(key, permissions);
+}
+
+/// Set how long fetched permissions are trusted.
+pub fn set_cache_ttl(ttl: Duration) {
+    init_cache().lock().unwrap().ttl = ttl;
+}
+
+/// Drop every cached permission for `chat_id`.
+pub fn invalidate_chat(chat_id: i64) {
+    init_cache().lock().unwrap().invalidate_chat(chat_id);
+}
+
+/// Invalidate cached permissions affected by a chat-participant update.
+pub fn observe_update(update: &Update) {
+    if let Some(chat_id) = participants_changed(update) {
+        invalidate_chat(chat_id);
+    }
+}
+
+/// The chat whose participants or admins `update` changes, if any.
+fn participants_changed(update: &Update) -> Option<i64> {
+    use tl::enums::Update as U;
+
+    let Update::Raw(raw) = update else {
+        return None;
+    };
+    let chat_id = match raw {
+        U::ChannelParticipant(u) => u.channel_id,
+        U::ChatParticipant(u) => u.chat_id,
+        U::ChatParticipantAdmin(u) => u.chat_id,
+        U::ChatParticipantAdd(u) => u.chat_id,
+        U::ChatParticipantDelete(u) => u.chat_id,
+        U::ChatParticipants(u) => match &u.participants {
+            tl::enums::ChatParticipants::Participants(p) => p.chat_id,
+            tl::enums::ChatParticipants::Forbidden(p) => p.chat_id,
+        },
+        _ => return None,
+    };
+    Some(chat_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(capacity: usize, ttl: Duration) -> PermissionCache {
+        PermissionCache::new(NonZeroUsize::new(capacity).unwrap(), ttl)
+    }
+
+    fn admin() -> ChatPermissions {
+        ChatPermissions {
+            is_admin: true,
+            rights: vec![AdminRight::BanUsers],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let mut fresh = cache(8, Duration::from_secs(60));
+        fresh.put((1, 2), admin());
+        assert!(fresh.get((1, 2)).unwrap().has(AdminRight::BanUsers));
+
+        let mut expired = cache(8, Duration::ZERO);
+        expired.put((1, 2), admin());
+        assert!(expired.get((1, 2)).is_none());
+        assert!(expired.entries.is_empty());
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let mut cache = cache(2, Duration::from_secs(60));
+        cache.put((1, 1), admin());
+        cache.put((1, 2), admin());
+        cache.get((1, 1));
+        cache.put((1, 3), admin());
+        assert!(cache.get((1, 1)).is_some());
+        assert!(cache.get((1, 2)).is_none());
+        assert!(cache.get((1, 3)).is_some());
+    }
+
+    #[test]
+    fn test_invalidate_chat() {
+        let mut cache = cache(8, Duration::from_secs(60));
+        cache.put((1, 1), admin());
+        cache.put((1, 2), admin());
+        cache.put((2, 1), admin());
+        cache.invalidate_chat(1);
+        assert!(cache.get((1, 1)).is_none());
+        assert!(cache.get((1, 2)).is_none());
+        assert!(cache.get((2, 1)).is_some());
+    }
+
+    #[test]
+    fn test_participant_updates_invalidate() {
+        let promoted = Update::Raw(
+            tl::types::UpdateChatParticipantAdmin {
+                chat_id: 7,
+                user_id: 42,
+                is_admin: true,
+                version: 1,
+            }
+            .into(),
+        );
+        let removed = Update::Raw(
+            tl::types::UpdateChatParticipantDelete {
+                chat_id: 8,
+                user_id: 42,
+                version: 1,
+            }
+            .into(),
+        );
+        let unrelated = Update::Raw(
+            tl::types::UpdateDcOptions {
+                dc_options: Vec::new(),
+            }
+            .into(),
+        );
+        assert_eq!(participants_changed(&promoted), Some(7));
+        assert_eq!(participants_changed(&removed), Some(8));
+        assert_eq!(participants_changed(&unrelated), None);
+    }
+
+    #[test]
+    fn test_anonymous_admin_has_no_rights() {
+        let permissions = ChatPermissions::anonymous_admin();
+        assert!(permissions.is_admin && permissions.anonymous);
+        assert!(permissions.has_all(&[]));
+        assert!(!permissions.has(AdminRight::BanUsers));
+    }
+}
@@ -0,0 +1,28 @@
+use std::sync::Arc;
+
+use grammers_client::{
+    grammers_tl_types as tl,
+    types::{Chat, ChatMap},
+};
+
+/// Chats and users that arrived alongside a raw update.
+///
+/// Only populated when the [`SwarmObject`](crate::swarm::SwarmObject) reads raw updates;
+/// otherwise every lookup returns `None`.
+#[derive(Clone, Default)]
+pub struct RawChats(Option<Arc<ChatMap>>);
+
+impl RawChats {
+    pub fn new(chats: Arc<ChatMap>) -> Self {
+        Self(Some(chats))
+    }
+
+    /// Resolve a peer mentioned by the update.
+    pub fn get(&self, peer: &tl::enums::Peer) -> Option<&Chat> {
+        self.0.as_deref()?.get(peer)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+}
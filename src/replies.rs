@@ -0,0 +1,195 @@
+use std::{num::NonZeroUsize, sync::Mutex};
+
+use grammers_client::{
+    Client, InputMessage, InvocationError,
+    session::PackedType,
+    types::{Message, Update},
+};
+use lru::LruCache;
+
+/// User and channel ids are separate namespaces, so messages are keyed by chat type too.
+type MessageKey = (PackedType, i64, i32);
+
+fn message_key(message: &Message) -> MessageKey {
+    let chat = message.chat().pack();
+    (chat.ty, chat.id, message.id())
+}
+
+/// Whether an update changed the text of a message.
+///
+/// The [`Swarm`](crate::swarm::Swarm) decides it once per update and injects it, so
+/// every handler sees the same answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextEdit {
+    /// The update is not an edit.
+    #[default]
+    NotEdited,
+    /// The edit changed the text, or the previous text is not remembered.
+    Changed,
+    /// The edit left the text as it was (a link preview loading, for example).
+    Unchanged,
+}
+
+/// Replies the bot sent and texts it saw on behalf of a single client.
+///
+/// Each [`SwarmObject`](crate::swarm::SwarmObject) owns one and injects it into every
+/// dispatch as `Arc<Replies>`.
+pub struct Replies {
+    /// Which message the bot sent in reply to which command.
+    replies: Mutex<LruCache<MessageKey, i32>>,
+    /// The last text seen for each message, to tell real edits apart.
+    texts: Mutex<LruCache<MessageKey, String>>,
+}
+
+impl Replies {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            replies: Mutex::new(LruCache::new(capacity)),
+            texts: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Record the text of a new or edited message and tell whether an edit changed it.
+    pub(crate) fn observe(&self, update: &Update) -> TextEdit {
+        match update {
+            Update::NewMessage(message) => {
+                self.observe_text(message_key(message), message.text(), false)
+            }
+            Update::MessageEdited(message) => {
+                self.observe_text(message_key(message), message.text(), true)
+            }
+            _ => TextEdit::NotEdited,
+        }
+    }
+
+    fn observe_text(&self, key: MessageKey, text: &str, edited: bool) -> TextEdit {
+        let mut texts = self.texts.lock().unwrap();
+        let unchanged = texts.get(&key).is_some_and(|previous| previous == text);
+        if !unchanged {
+            texts.put(key, text.to_string());
+        }
+        match (edited, unchanged) {
+            (false, _) => TextEdit::NotEdited,
+            (true, false) => TextEdit::Changed,
+            (true, true) => TextEdit::Unchanged,
+        }
+    }
+
+    /// Id of the reply the bot sent to `message`, if it is still remembered.
+    pub fn previous_reply(&self, message: &Message) -> Option<i32> {
+        self.replies
+            .lock()
+            .unwrap()
+            .get(&message_key(message))
+            .copied()
+    }
+
+    /// Reply to `message`, or edit the earlier reply if `message` is an edited command.
+    ///
+    /// Use this in commands dispatched through
+    /// [`UpdateFilterExt::filter_new_or_edited_message`](crate::filters::UpdateFilterExt::filter_new_or_edited_message)
+    /// so that re-running the command updates the bot's answer in place.
+    ///
+    /// An edit that leaves the reply unchanged (`MESSAGE_NOT_MODIFIED`) counts as success.
+    pub async fn reply_or_edit<M: Into<InputMessage>>(
+        &self,
+        client: &Client,
+        message: &Message,
+        content: M,
+    ) -> Result<(), InvocationError> {
+        if message.edit_date().is_some()
+            && let Some(reply_id) = self.previous_reply(message)
+        {
+            return match client
+                .edit_message(message.chat().pack(), reply_id, content)
+                .await
+            {
+                Err(InvocationError::Rpc(err)) if err.name == "MESSAGE_NOT_MODIFIED" => Ok(()),
+                result => result,
+            };
+        }
+
+        let reply = message.reply(content).await?;
+        self.replies
+            .lock()
+            .unwrap()
+            .put(message_key(message), reply.id());
+        Ok(())
+    }
+}
+
+impl Default for Replies {
+    fn default() -> Self {
+        Self::new(NonZeroUsize::new(4096).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: MessageKey = (PackedType::Chat, 1, 10);
+
+    #[test]
+    fn test_new_message_is_not_an_edit() {
+        let replies = Replies::default();
+        assert_eq!(
+            replies.observe_text(KEY, "/ban 10m", false),
+            TextEdit::NotEdited
+        );
+    }
+
+    #[test]
+    fn test_edit_changing_text() {
+        let replies = Replies::default();
+        replies.observe_text(KEY, "/ban 10m", false);
+        assert_eq!(
+            replies.observe_text(KEY, "/ban 1h", true),
+            TextEdit::Changed
+        );
+        assert_eq!(
+            replies.observe_text(KEY, "/ban 10m", true),
+            TextEdit::Changed
+        );
+    }
+
+    #[test]
+    fn test_edit_keeping_text() {
+        let replies = Replies::default();
+        replies.observe_text(KEY, "/ban 10m", false);
+        assert_eq!(
+            replies.observe_text(KEY, "/ban 10m", true),
+            TextEdit::Unchanged
+        );
+        assert_eq!(
+            replies.observe_text(KEY, "/ban 10m", true),
+            TextEdit::Unchanged
+        );
+    }
+
+    #[test]
+    fn test_unknown_message_edit_counts_as_changed() {
+        let replies = Replies::default();
+        assert_eq!(
+            replies.observe_text(KEY, "/ban 10m", true),
+            TextEdit::Changed
+        );
+    }
+
+    #[test]
+    fn test_texts_are_kept_per_client_and_chat_type() {
+        let first = Replies::default();
+        let second = Replies::default();
+        first.observe_text(KEY, "/ban 10m", false);
+        assert_eq!(
+            second.observe_text(KEY, "/ban 10m", true),
+            TextEdit::Changed
+        );
+
+        let channel = (PackedType::Broadcast, KEY.1, KEY.2);
+        assert_eq!(
+            first.observe_text(channel, "/ban 10m", true),
+            TextEdit::Changed
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use std::{ops::Deref, sync::Arc, time::Duration};
+
+use grammers_client::{Client, InvocationError, grammers_tl_types as tl};
+
+use crate::{Event, EventListener, event};
+
+/// When and how long to wait before retrying a failed Telegram call.
+///
+/// `FLOOD_WAIT` errors are retried after the wait Telegram asks for, unless it is longer
+/// than `max_flood_wait`. Internal server errors and connection failures are retried with
+/// exponential backoff. Any other error, such as `MESSAGE_NOT_MODIFIED` or a response
+/// that failed to deserialize, is final.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+    pub max_flood_wait: Duration,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            max_flood_wait: Duration::from_secs(60),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before the next attempt, or `None` if `error` after `attempt`
+    /// attempts should not be retried.
+    pub fn delay(&self, error: &InvocationError, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        match error {
+            InvocationError::Rpc(rpc) => self.rpc_delay(&rpc.name, rpc.code, rpc.value, attempt),
+            // The connection failed; the request may not even have reached Telegram.
+            InvocationError::Io(_) | InvocationError::Transport(_) | InvocationError::Dropped => {
+                Some(self.backoff(attempt))
+            }
+            // E.g. a response that could not be deserialized; it would fail again.
+            _ => None,
+        }
+    }
+
+    fn rpc_delay(
+        &self,
+        name: &str,
+        code: i32,
+        value: Option<u32>,
+        attempt: u32,
+    ) -> Option<Duration> {
+        match name {
+            "FLOOD_WAIT" | "FLOOD_PREMIUM_WAIT" | "SLOWMODE_WAIT" => {
+                let wait = Duration::from_secs(value.unwrap_or(1).into());
+                (wait <= self.max_flood_wait).then_some(wait)
+            }
+            _ if code >= 500 || code == -503 => Some(self.backoff(attempt)),
+            _ => None,
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Run `op` until it succeeds or `policy` gives up, emitting [`Event::Retrying`] before
+/// every retry. `error_of` picks the Telegram error out of the output, if any.
+pub(crate) async fn retrying<T, F, Fut>(
+    policy: &RetryPolicy,
+    listeners: &[Arc<dyn EventListener>],
+    handler: Option<usize>,
+    mut op: F,
+    error_of: fn(&T) -> Option<&InvocationError>,
+) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = T>,
+{
+    let mut attempt = 1;
+    loop {
+        let output = op().await;
+        let Some(error) = error_of(&output) else {
+            return output;
+        };
+        let Some(delay) = policy.delay(error, attempt) else {
+            return output;
+        };
+
+        let _ = event::emit(
+            listeners,
+            Event::Retrying {
+                handler,
+                attempt,
+                delay,
+                error,
+            },
+        )
+        .await;
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// A [`Client`] whose calls are retried according to a [`RetryPolicy`].
+///
+/// Injected into every dispatch by [`Swarm`](crate::Swarm), using the router's retry
+/// policy and event listeners. Methods that are not wrapped are reachable through
+/// [`Deref`] and are not retried; wrap them with [`RetryClient::call`].
+#[derive(Clone)]
+pub struct RetryClient {
+    client: Client,
+    policy: RetryPolicy,
+    listeners: Vec<Arc<dyn EventListener>>,
+}
+
+impl RetryClient {
+    pub fn new(client: Client, policy: RetryPolicy) -> Self {
+        Self {
+            client,
+            policy,
+            listeners: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_listeners(mut self, listeners: Vec<Arc<dyn EventListener>>) -> Self {
+        self.listeners = listeners;
+        self
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    pub async fn invoke<R: tl::RemoteCall>(
+        &self,
+        request: &R,
+    ) -> Result<R::Return, InvocationError> {
+        self.call(|client| async move { client.invoke(request).await })
+            .await
+    }
+
+    /// Retry an arbitrary call, e.g.
+    /// `client.call(|c| async move { c.send_message(chat, "hi").await }).await`.
+    pub async fn call<T, F, Fut>(&self, mut op: F) -> Result<T, InvocationError>
+    where
+        F: FnMut(Client) -> Fut,
+        Fut: Future<Output = Result<T, InvocationError>>,
+    {
+        retrying(
+            &self.policy,
+            &self.listeners,
+            None,
+            || op(self.client.clone()),
+            |result| result.as_ref().err(),
+        )
+        .await
+    }
+}
+
+impl Deref for RetryClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flood_wait() {
+        let policy = RetryPolicy::default();
+        assert_eq!(
+            policy.rpc_delay("FLOOD_WAIT", 420, Some(5), 1),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(policy.rpc_delay("FLOOD_WAIT", 420, Some(3600), 1), None);
+    }
+
+    #[test]
+    fn test_rpc_errors() {
+        let policy = RetryPolicy::default();
+        assert_eq!(
+            policy.rpc_delay("INTERNAL", 500, None, 1),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            policy.rpc_delay("INTERNAL", 500, None, 2),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(policy.rpc_delay("MESSAGE_NOT_MODIFIED", 400, None, 1), None);
+    }
+
+    #[test]
+    fn test_transport_errors() {
+        let policy = RetryPolicy::default();
+        let error = InvocationError::Io(std::io::Error::other("connection reset"));
+        assert_eq!(policy.delay(&error, 1), Some(Duration::from_millis(500)));
+        assert_eq!(policy.delay(&error, policy.max_attempts), None);
+        assert_eq!(
+            policy.delay(&InvocationError::Dropped, 1),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 20,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(10), policy.max_backoff);
+    }
+}
@@ -1,51 +1,520 @@
-use std::ops::ControlFlow;
+use std::{
+    ops::ControlFlow,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use dptree::{Handler, di::DependencyMap};
+use grammers_client::{Client, Update};
+use tokio::{sync::oneshot, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
 
-use crate::{Event, EventListener, GenericResult, commands::CommandMeta};
+use crate::{
+    Event, EventListener, GenericResult, SharedError,
+    cache::UpdateCache,
+    commands::CommandMeta,
+    errors::{Error, HandlerId, default_error_handler},
+    event::{self, MatchSignal},
+    middleware::{Endpoint, Middleware, ModuleMiddlewares, Next},
+    panic,
+    retry::{RetryClient, RetryPolicy, retrying},
+    scope::{self, Factory, Finalize},
+    timeout::{self, run_with_timeout},
+};
+
+/// How [`Router::dispatch`] runs its handlers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DispatchStrategy {
+    /// Run every handler at once; any number of them may match.
+    #[default]
+    Concurrent,
+    /// Try handlers one by one, highest priority first, until one matches.
+    Sequential,
+    /// Visit groups in ascending order. Within a group handlers are tried by priority and
+    /// only the first match runs, then dispatch moves on to the next group.
+    Grouped,
+}
+
+#[derive(Clone)]
+struct Route {
+    handler: Handler<'static, DependencyMap, GenericResult>,
+    priority: i32,
+    group: i32,
+    retry: Option<RetryPolicy>,
+    panics: Arc<AtomicUsize>,
+}
+
+/// What became of a single handler during a dispatch.
+#[derive(Debug, Clone)]
+pub enum HandlerResult {
+    Unmatched,
+    Ok,
+    /// The handler failed; the error was passed to the error handler.
+    Err(SharedError),
+}
+
+/// Where a handler sits: its index in the router that was dispatched, followed by its
+/// index in each included router on the way down. Displayed as `2.0`.
+///
+/// An included router's own path stands for the router itself, e.g. when its
+/// middlewares failed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct HandlerPath(pub Vec<usize>);
+
+impl std::fmt::Display for HandlerPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (depth, index) in self.0.iter().enumerate() {
+            if depth > 0 {
+                f.write_str(".")?;
+            }
+            write!(f, "{index}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The path of the handler being run, injected so that included routers know where
+/// they sit.
+#[derive(Clone)]
+struct CurrentPath(HandlerPath);
+
+#[derive(Debug, Clone)]
+pub struct HandlerOutcome {
+    pub handler: HandlerPath,
+    pub result: HandlerResult,
+    pub elapsed: Duration,
+}
+
+impl HandlerOutcome {
+    pub fn matched(&self) -> bool {
+        !matches!(self.result, HandlerResult::Unmatched)
+    }
+
+    fn nested_in(mut self, index: usize) -> Self {
+        self.handler.0.insert(0, index);
+        self
+    }
+}
+
+/// The result of [`Router::dispatch`].
+#[derive(Debug, Clone, Default)]
+pub struct DispatchOutcome {
+    /// A listener cancelled the dispatch in [`Event::BeginDispatch`].
+    pub cancelled: bool,
+    /// Every handler that was tried, in the order they were tried. Included routers
+    /// are replaced by the handlers they tried.
+    pub handlers: Vec<HandlerOutcome>,
+    /// What the fallback handler did, if it ran.
+    pub fallback: Option<HandlerResult>,
+    /// An error returned by the router-wide middlewares themselves rather than by a
+    /// handler, e.g. a rejected ban-list check.
+    pub middleware: Option<SharedError>,
+}
+
+impl DispatchOutcome {
+    /// Whether any handler matched, not counting the fallback handler.
+    pub fn matched(&self) -> bool {
+        self.handlers.iter().any(HandlerOutcome::matched)
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &SharedError> {
+        self.handlers
+            .iter()
+            .map(|outcome| &outcome.result)
+            .chain(&self.fallback)
+            .filter_map(|result| match result {
+                HandlerResult::Err(err) => Some(err),
+                _ => None,
+            })
+            .chain(&self.middleware)
+    }
+}
+
+/// The error handler of the innermost router that set one, so errors in a sub-router
+/// without its own handler reach the parent's.
+#[derive(Clone)]
+struct ScopedErrorHandler(Handler<'static, DependencyMap, ()>);
+
+/// Where an included router leaves the outcomes of its handlers for the parent.
+#[derive(Clone, Default)]
+struct NestedOutcomes(Arc<Mutex<Option<Vec<HandlerOutcome>>>>);
+
+impl NestedOutcomes {
+    /// The outcomes to report for the handler at `index`, whose own outcome is `outcome`.
+    ///
+    /// An included router is replaced by its handlers, but a failure of the router
+    /// handler itself, like a timeout, is kept. Its [`Error::Handled`] only repeats the
+    /// errors of the handlers and is left out.
+    fn resolve(&self, index: usize, outcome: HandlerOutcome) -> Vec<HandlerOutcome> {
+        let mut outcomes: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .take()
+            .into_iter()
+            .flatten()
+            .map(|nested| nested.nested_in(index))
+            .collect();
+        let failed = matches!(
+            &outcome.result,
+            HandlerResult::Err(err) if !matches!(**err, Error::Handled(_))
+        );
+        if outcomes.is_empty() || failed {
+            outcomes.push(outcome);
+        }
+        outcomes
+    }
+}
+
+/// Enables or disables a router at runtime, see [`Router::toggle`].
+#[derive(Debug, Clone)]
+pub struct RouterToggle(Arc<AtomicBool>);
+
+impl RouterToggle {
+    pub fn enable(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn disable(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
 
 #[derive(Clone)]
 pub struct Router {
-    handlers: Vec<Handler<'static, DependencyMap, GenericResult>>,
+    handlers: Vec<Route>,
+    strategy: DispatchStrategy,
     pub commands: Vec<CommandMeta>,
     pub error_handler: Handler<'static, DependencyMap, ()>,
-    // event_listeners: Vec<Box<dyn EventListener>>,
+    has_error_handler: bool,
+    module: Option<&'static str>,
+    filter: Option<Handler<'static, DependencyMap, GenericResult>>,
+    enabled: Arc<AtomicBool>,
+    timeout: Option<Duration>,
+    panic_limit: Option<usize>,
+    retry_policy: Option<RetryPolicy>,
+    fallback_handler: Option<Handler<'static, DependencyMap, GenericResult>>,
+    factories: Vec<Arc<dyn Factory>>,
+    event_listeners: Vec<Arc<dyn EventListener>>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    module_middlewares: ModuleMiddlewares,
 }
 
 impl Router {
     pub fn new() -> Self {
         Self {
             handlers: Vec::new(),
+            strategy: DispatchStrategy::default(),
             commands: Vec::new(),
-            error_handler: dptree::entry(),
-            // event_listeners: Vec::new(),
+            error_handler: default_error_handler(),
+            has_error_handler: false,
+            module: None,
+            filter: None,
+            enabled: Arc::new(AtomicBool::new(true)),
+            timeout: None,
+            panic_limit: None,
+            retry_policy: None,
+            fallback_handler: None,
+            factories: Vec::new(),
+            event_listeners: Vec::new(),
+            middlewares: Vec::new(),
+            module_middlewares: ModuleMiddlewares::default(),
         }
     }
 
     pub fn set_error_handler(&mut self, handler: Handler<'static, DependencyMap, ()>) -> &mut Self {
         self.error_handler = handler;
+        self.has_error_handler = true;
+        self
+    }
+
+    /// Fail handlers that run longer than `timeout` with
+    /// [`DispatchError::Timeout`](crate::errors::DispatchError::Timeout).
+    ///
+    /// Each handler gets its own child of the dispatch's [`CancellationToken`], which is
+    /// cancelled on timeout.
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Run `handler` when no other handler matched, e.g. to tell users in private chats
+    /// that the bot did not understand them.
+    ///
+    /// Only the router that [`dispatch`](Router::dispatch) is called on runs its fallback
+    /// handler, not included routers.
+    pub fn set_fallback_handler(
+        &mut self,
+        handler: Handler<'static, DependencyMap, GenericResult>,
+    ) -> &mut Self {
+        self.fallback_handler = Some(handler);
+        self
+    }
+
+    /// Retry Telegram calls made through the [`RetryClient`] returned from
+    /// [`Router::retry_client`].
+    ///
+    /// Handlers themselves are not re-run; opt in per handler with
+    /// [`Router::add_with_retry`].
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) -> &mut Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// A client whose calls are retried by this router's policy, reporting retries to
+    /// its event listeners.
+    pub fn retry_client(&self, client: Client) -> RetryClient {
+        RetryClient::new(client, self.retry_policy.unwrap_or_default())
+            .with_listeners(self.event_listeners.clone())
+    }
+
+    /// Stop running a handler once it panicked `limit` times.
+    pub fn set_panic_limit(&mut self, limit: usize) -> &mut Self {
+        self.panic_limit = Some(limit);
+        self
+    }
+
+    /// Set the module of commands registered on this router that do not name one.
+    ///
+    /// Call this before registering commands.
+    pub fn set_module(&mut self, module: &'static str) -> &mut Self {
+        self.module = Some(module);
+        self
+    }
+
+    pub fn module(&self) -> Option<&'static str> {
+        self.module
+    }
+
+    /// Only consider this router's handlers for updates passing `filter`.
+    ///
+    /// Values injected by the filter are visible to every handler of the router.
+    pub fn set_filter(
+        &mut self,
+        filter: Handler<'static, DependencyMap, GenericResult>,
+    ) -> &mut Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// A handle to enable or disable this router, and every router it includes, while it
+    /// is running.
+    pub fn toggle(&self) -> RouterToggle {
+        RouterToggle(self.enabled.clone())
+    }
+
+    pub fn set_strategy(&mut self, strategy: DispatchStrategy) -> &mut Self {
+        self.strategy = strategy;
         self
     }
 
     pub fn add(&mut self, handler: Handler<'static, DependencyMap, GenericResult>) -> &mut Self {
-        self.handlers.push(handler);
+        self.add_route(handler, 0, 0)
+    }
+
+    /// Add a handler tried before handlers of lower `priority` in sequential and grouped
+    /// dispatch.
+    pub fn add_with_priority(
+        &mut self,
+        handler: Handler<'static, DependencyMap, GenericResult>,
+        priority: i32,
+    ) -> &mut Self {
+        self.add_route(handler, priority, 0)
+    }
+
+    /// Add a handler that is re-run by `policy` when it fails with a retryable
+    /// [`Error::Telegram`].
+    ///
+    /// Only handlers whose side effects are safe to repeat should be retried.
+    pub fn add_with_retry(
+        &mut self,
+        handler: Handler<'static, DependencyMap, GenericResult>,
+        policy: RetryPolicy,
+    ) -> &mut Self {
+        self.add(handler);
+        if let Some(route) = self.handlers.last_mut() {
+            route.retry = Some(policy);
+        }
+        self
+    }
+
+    /// Add a handler to `group` for [`DispatchStrategy::Grouped`].
+    pub fn add_to_group(
+        &mut self,
+        group: i32,
+        handler: Handler<'static, DependencyMap, GenericResult>,
+    ) -> &mut Self {
+        self.add_route(handler, 0, group)
+    }
+
+    pub fn add_route(
+        &mut self,
+        handler: Handler<'static, DependencyMap, GenericResult>,
+        priority: i32,
+        group: i32,
+    ) -> &mut Self {
+        self.handlers.push(Route {
+            handler,
+            priority,
+            group,
+            retry: None,
+            panics: Arc::new(AtomicUsize::new(0)),
+        });
         self
     }
 
-    pub fn add_command(&mut self, command: CommandMeta) -> &mut Self {
+    pub fn add_command(&mut self, mut command: CommandMeta) -> &mut Self {
+        if command.module.is_none() {
+            command.module = self.module;
+        }
         self.commands.push(command);
         self
     }
 
+    /// Add `router` as a single handler of this one.
+    ///
+    /// The sub-router keeps its own strategy, filter, middlewares, event listeners and
+    /// error handler; without an error handler its errors go to this router's. Its
+    /// commands, module middlewares and factories are copied here, so include it once it
+    /// is fully set up.
+    pub fn include(&mut self, router: Router) -> &mut Self {
+        self.include_with_priority(router, 0)
+    }
+
+    pub fn include_with_priority(&mut self, router: Router, priority: i32) -> &mut Self {
+        self.commands.extend(router.commands.iter().cloned());
+        self.factories.extend(router.factories.iter().cloned());
+        for (module, middlewares) in &router.module_middlewares.0 {
+            self.module_middlewares
+                .0
+                .entry(module)
+                .or_default()
+                .extend(middlewares.iter().cloned());
+        }
+        self.add_with_priority(router.into_handler(), priority)
+    }
+
+    /// Turn this router into a handler that matches when any of its handlers matched.
+    ///
+    /// Errors are passed to the error handler inside the router. The handler then yields
+    /// [`Error::Handled`] with them, which a parent router does not report again.
+    pub fn into_handler(self) -> Handler<'static, DependencyMap, GenericResult> {
+        let enabled = self.enabled.clone();
+        let filter = self.filter.clone();
+        let router = Arc::new(self);
+        let run = dptree::from_fn(move |deps: DependencyMap, _cont| {
+            let router = router.clone();
+            async move {
+                let (outcome, _) = router.run(deps.clone()).await;
+                let matched = outcome.matched() || outcome.middleware.is_some();
+                let errors: Vec<_> = outcome.errors().cloned().collect();
+                if let Some(nested) = deps.clone().remove::<NestedOutcomes>() {
+                    let mut outcomes = outcome.handlers;
+                    if let Some(err) = outcome.middleware {
+                        outcomes.push(HandlerOutcome {
+                            handler: HandlerPath::default(),
+                            result: HandlerResult::Err(err),
+                            elapsed: Duration::ZERO,
+                        });
+                    }
+                    *nested.0.lock().unwrap() = Some(outcomes);
+                }
+                if !errors.is_empty() {
+                    ControlFlow::Break(Err(Error::Handled(errors)))
+                } else if matched {
+                    ControlFlow::Break(Ok(()))
+                } else {
+                    ControlFlow::Continue(deps)
+                }
+            }
+        });
+
+        let entry = dptree::filter(move || enabled.load(Ordering::Relaxed));
+        match filter {
+            Some(filter) => entry.chain(filter).chain(run),
+            None => entry.chain(run),
+        }
+    }
+
+    /// Make a `T` available to handlers as [`Scoped<T>`](crate::scope::Scoped), created by
+    /// `factory` from the update the first time a handler asks for it in a dispatch.
+    pub fn add_factory<T, F, Fut>(&mut self, factory: F) -> &mut Self
+    where
+        T: Send + Sync + 'static,
+        F: Fn(Update) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<T, Error>> + Send + 'static,
+    {
+        self.factories.push(scope::factory(factory));
+        self
+    }
+
+    /// Like [`Router::add_factory`], passing the value to `finalizer` once the dispatch
+    /// is done, together with its result: the error if one handler failed, or
+    /// [`Error::Handled`] listing them if several did. Handlers of included routers count.
+    ///
+    /// The finalizer only runs if the value was created, e.g. to commit or roll back a
+    /// database transaction.
+    pub fn add_factory_with_finalizer<T, F, Fut, G, GFut>(
+        &mut self,
+        factory: F,
+        finalizer: G,
+    ) -> &mut Self
+    where
+        T: Send + Sync + 'static,
+        F: Fn(Update) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<T, Error>> + Send + 'static,
+        G: Fn(T, Result<(), SharedError>) -> GFut + Send + Sync + 'static,
+        GFut: Future<Output = GenericResult> + Send + 'static,
+    {
+        self.factories
+            .push(scope::factory_with_finalizer(factory, finalizer));
+        self
+    }
+
+    /// Listeners receive every [`Event`] in the order they were added.
     pub fn add_event_listener<C: EventListener>(&mut self, listener: C) -> &mut Self {
-        // self.event_listeners.push(Box::new(listener));
+        self.event_listeners.push(Arc::new(listener));
+        self
+    }
+
+    /// Wrap the handlers of this router, once per dispatch. Middlewares run in the order
+    /// they were added.
+    ///
+    /// They run before any filter; use [`Router::add_module_middleware`] for logic that
+    /// needs to know which command matched.
+    pub fn add_middleware<M: Middleware>(&mut self, middleware: M) -> &mut Self {
+        self.middlewares.push(Arc::new(middleware));
         self
     }
 
-    pub async fn emit(&self, event: Event<'_>) {
-        // for listener in &self.event_listeners {
-        // listener.handle(&event).await;
-        // }
+    /// Wrap the endpoints of commands whose [`CommandMeta::module`] is `module`, for
+    /// handlers built with
+    /// [`HandlerExt::filter_scoped_command`](crate::filters::HandlerExt::filter_scoped_command).
+    ///
+    /// Unlike router-wide middlewares these run after the command matched, so
+    /// [`CommandInput`](crate::commands::CommandInput) is available in the dependencies.
+    pub fn add_module_middleware<M: Middleware>(
+        &mut self,
+        module: &'static str,
+        middleware: M,
+    ) -> &mut Self {
+        self.module_middlewares
+            .0
+            .entry(module)
+            .or_default()
+            .push(Arc::new(middleware));
+        self
+    }
+
+    pub async fn emit(&self, event: Event<'_>) -> ControlFlow<()> {
+        event::emit(&self.event_listeners, event).await
     }
 
     pub(crate) fn reinit_command_regexes(&mut self, bot_username: &str) -> &mut Self {
@@ -55,31 +524,383 @@ impl Router {
         self
     }
 
-    pub async fn dispatch(&self, deps: DependencyMap) {
-        let mut tasks = Vec::new();
+    /// Handler indices in the order they are tried: by group if `by_group`, then highest
+    /// priority first, then in registration order.
+    fn ordered(&self, by_group: bool) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.handlers.len()).collect();
+        order.sort_by_key(|&index| {
+            let route = &self.handlers[index];
+            let group = if by_group { route.group } else { 0 };
+            (group, -route.priority, index)
+        });
+        order
+    }
 
-        let mut deps = deps;
-        self.emit(Event::BeginDispatch { deps: &mut deps }).await;
-
-        for handler in self.handlers.clone() {
-            let mut deps_clone = deps.clone();
-            let error_handler = self.error_handler.clone();
-            let task = tokio::spawn(async move {
-                let dispatched = handler.dispatch(deps_clone.clone()).await;
-                if let ControlFlow::Break(Err(err)) = dispatched {
-                    deps_clone.insert(err);
-                    error_handler.dispatch(deps_clone).await;
+    /// Run the handler at `index` on its own task.
+    ///
+    /// Timeouts and panics are reported to the error handler like any other error.
+    fn spawn_handler(&self, index: usize, deps: &DependencyMap) -> JoinHandle<Vec<HandlerOutcome>> {
+        let route = &self.handlers[index];
+        let handler = route.handler.clone();
+        let panics = route.panics.clone();
+        let retry = route.retry;
+        let disabled = self
+            .panic_limit
+            .is_some_and(|limit| panics.load(Ordering::Relaxed) >= limit);
+        let mut deps_clone = deps.clone();
+        let error_handler = deps
+            .clone()
+            .remove::<ScopedErrorHandler>()
+            .map_or_else(|| self.error_handler.clone(), |scoped| scoped.0.clone());
+        let listeners = self.event_listeners.clone();
+        let timeout = self.timeout;
+        let token = timeout::token(deps).child_token();
+        let signal = MatchSignal::new(listeners.clone(), index);
+        let mut path = deps
+            .clone()
+            .remove::<CurrentPath>()
+            .map_or_else(HandlerPath::default, |current| current.0.clone());
+        path.0.push(index);
+        deps_clone.insert(CurrentPath(path.clone()));
+        deps_clone.insert(token.clone());
+        let nested = NestedOutcomes::default();
+        deps_clone.insert(signal.clone());
+        deps_clone.insert(nested.clone());
+        let task = async move {
+            let started = Instant::now();
+            let elapsed = || signal.matched_at().unwrap_or(started).elapsed();
+            let outcome = |result| HandlerOutcome {
+                handler: HandlerPath(vec![index]),
+                result,
+                elapsed: elapsed(),
+            };
+            if disabled {
+                return outcome(HandlerResult::Unmatched);
+            }
+
+            let id = HandlerId::Path(path);
+            let run = || async {
+                match handler.dispatch(deps_clone.clone()).await {
+                    ControlFlow::Break(result) => Some(result),
+                    ControlFlow::Continue(_) => None,
+                }
+            };
+            let running = async {
+                match &retry {
+                    Some(policy) => {
+                        retrying(
+                            policy,
+                            &listeners,
+                            Some(index),
+                            run,
+                            |result| match result {
+                                Some(Err(Error::Telegram(err))) => Some(err),
+                                _ => None,
+                            },
+                        )
+                        .await
+                    }
+                    None => run().await,
+                }
+            };
+            let dispatched =
+                panic::catch(id.clone(), run_with_timeout(id, timeout, &token, running))
+                    .await
+                    .inspect_err(|_| {
+                        panics.fetch_add(1, Ordering::Relaxed);
+                    })
+                    .and_then(|result| result)
+                    .unwrap_or_else(|err| Some(Err(err.into())));
+            let Some(result) = dispatched else {
+                return outcome(HandlerResult::Unmatched);
+            };
+
+            signal.fire().await;
+            match result {
+                Ok(()) => {
+                    let _ = event::emit(
+                        &listeners,
+                        Event::HandlerFinished {
+                            handler: index,
+                            elapsed: elapsed(),
+                        },
+                    )
+                    .await;
+                    outcome(HandlerResult::Ok)
                 }
+                Err(err) => {
+                    let err: SharedError = Arc::new(err);
+                    let _ = event::emit(
+                        &listeners,
+                        Event::HandlerFailed {
+                            handler: index,
+                            error: &err,
+                        },
+                    )
+                    .await;
+                    let outcome = outcome(HandlerResult::Err(err.clone()));
+                    if !matches!(*err, Error::Handled(_)) {
+                        deps_clone.insert(err);
+                        error_handler.dispatch(deps_clone).await;
+                    }
+                    outcome
+                }
+            }
+        };
+        tokio::spawn(async move {
+            let outcome = task.await;
+            nested.resolve(index, outcome)
+        })
+    }
+
+    pub async fn dispatch(&self, deps: DependencyMap) -> DispatchOutcome {
+        let mut deps = deps;
+        deps.insert(UpdateCache::new());
+        deps.insert(self.module_middlewares.clone());
+        deps.insert(ScopedErrorHandler(self.error_handler.clone()));
+        deps.insert(timeout::token(&deps));
+        let _ = self.emit(Event::UpdateReceived { deps: &deps }).await;
+        if self
+            .emit(Event::BeginDispatch { deps: &mut deps })
+            .await
+            .is_break()
+        {
+            return DispatchOutcome {
+                cancelled: true,
+                ..Default::default()
+            };
+        }
+
+        let update = deps.clone().remove::<Update>().map(Arc::unwrap_or_clone);
+        let scopes: Vec<_> = self
+            .factories
+            .iter()
+            .map(|factory| factory.begin(update.clone(), &mut deps))
+            .collect();
+
+        let (mut outcome, reached) = if self.enabled.load(Ordering::Relaxed) {
+            self.run(deps.clone()).await
+        } else {
+            (DispatchOutcome::default(), false)
+        };
+        // A disabled router, or middlewares that skipped the handlers, also skip the
+        // fallback.
+        if reached && !outcome.matched() {
+            let _ = self.emit(Event::Unhandled { deps: &deps }).await;
+            if let Some(fallback) = &self.fallback_handler {
+                outcome.fallback = Some(self.run_fallback(fallback, deps.clone()).await);
+            }
+        }
+
+        let mut errors: Vec<_> = outcome.errors().cloned().collect();
+        let result = match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(Arc::new(Error::Handled(errors))),
+        };
+        for scope in scopes {
+            scope.finalize(result.clone()).await;
+        }
+        let _ = self.emit(Event::EndDispatch { deps }).await;
+        outcome
+    }
+
+    async fn run_fallback(
+        &self,
+        fallback: &Handler<'static, DependencyMap, GenericResult>,
+        mut deps: DependencyMap,
+    ) -> HandlerResult {
+        match fallback.dispatch(deps.clone()).await {
+            ControlFlow::Continue(_) => HandlerResult::Unmatched,
+            ControlFlow::Break(Ok(())) => HandlerResult::Ok,
+            ControlFlow::Break(Err(err)) => {
+                let err: SharedError = Arc::new(err);
+                deps.insert(err.clone());
+                self.error_handler.dispatch(deps).await;
+                HandlerResult::Err(err)
+            }
+        }
+    }
+
+    /// Run the handlers through the router-wide middlewares.
+    ///
+    /// Also returns whether the middlewares let the handlers run at all.
+    async fn run(&self, mut deps: DependencyMap) -> (DispatchOutcome, bool) {
+        if self.has_error_handler {
+            deps.insert(ScopedErrorHandler(self.error_handler.clone()));
+        }
+        if self.middlewares.is_empty() {
+            let handlers = self.run_handlers(deps).await;
+            let outcome = DispatchOutcome {
+                handlers,
+                ..Default::default()
+            };
+            return (outcome, true);
+        }
+
+        // The endpoint hands the dependencies over and waits for the handlers, which run
+        // here on the same task and can borrow the router.
+        let (enter, entered) = oneshot::channel();
+        let (done, finished) = oneshot::channel();
+        let endpoint: Endpoint = Box::new(move |deps| {
+            Box::pin(async move {
+                let _ = enter.send(deps);
+                finished.await.unwrap_or(Ok(()))
+            })
+        });
+        let chain = Next::new(self.middlewares.clone(), endpoint).run(deps.clone());
+        let handlers = async {
+            let handlers = self.run_handlers(entered.await.ok()?).await;
+            let errors: Vec<_> = handlers
+                .iter()
+                .filter_map(|outcome| match &outcome.result {
+                    HandlerResult::Err(err) => Some(err.clone()),
+                    _ => None,
+                })
+                .collect();
+            let _ = done.send(if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(Error::Handled(errors))
             });
+            Some(handlers)
+        };
 
-            tasks.push(task);
+        let (result, handlers) = tokio::join!(chain, handlers);
+        let reached = handlers.is_some();
+        let mut outcome = DispatchOutcome {
+            handlers: handlers.unwrap_or_default(),
+            ..Default::default()
+        };
+        match result {
+            Ok(()) | Err(Error::Handled(_)) => {}
+            Err(err) => {
+                let err: SharedError = Arc::new(err);
+                outcome.middleware = Some(err.clone());
+                let error_handler = deps
+                    .clone()
+                    .remove::<ScopedErrorHandler>()
+                    .map_or_else(|| self.error_handler.clone(), |scoped| scoped.0.clone());
+                deps.insert(err);
+                error_handler.dispatch(deps).await;
+            }
         }
+        (outcome, reached)
+    }
 
-        for task in tasks {
-            let _ = task.await;
+    /// Run the handlers according to the strategy.
+    ///
+    /// Handlers that were never tried are left out of the result.
+    async fn run_handlers(&self, deps: DependencyMap) -> Vec<HandlerOutcome> {
+        let mut outcomes = Vec::new();
+        match self.strategy {
+            DispatchStrategy::Concurrent => {
+                let tasks: Vec<_> = (0..self.handlers.len())
+                    .map(|index| (index, self.spawn_handler(index, &deps)))
+                    .collect();
+                for (index, task) in tasks {
+                    outcomes.extend(joined(index, task).await);
+                }
+            }
+            DispatchStrategy::Sequential => {
+                for index in self.ordered(false) {
+                    let tried = joined(index, self.spawn_handler(index, &deps)).await;
+                    let matched = tried.iter().any(HandlerOutcome::matched);
+                    outcomes.extend(tried);
+                    if matched {
+                        break;
+                    }
+                }
+            }
+            DispatchStrategy::Grouped => {
+                let order = self.ordered(true);
+                for group in
+                    order.chunk_by(|&a, &b| self.handlers[a].group == self.handlers[b].group)
+                {
+                    for &index in group {
+                        let tried = joined(index, self.spawn_handler(index, &deps)).await;
+                        let matched = tried.iter().any(HandlerOutcome::matched);
+                        outcomes.extend(tried);
+                        if matched {
+                            break;
+                        }
+                    }
+                }
+            }
         }
+        outcomes
+    }
+}
+
+/// Wait for a handler task.
+///
+/// Panics are caught inside the task, so a task that failed anyway was aborted
+/// mid-handler; it counts as matched and failed rather than unhandled.
+async fn joined(index: usize, task: JoinHandle<Vec<HandlerOutcome>>) -> Vec<HandlerOutcome> {
+    task.await.unwrap_or_else(|err| {
+        vec![HandlerOutcome {
+            handler: HandlerPath(vec![index]),
+            result: HandlerResult::Err(Arc::new(Error::internal(err))),
+            elapsed: Duration::ZERO,
+        }]
+    })
+}
+
+/// A [`Router`] that can be replaced while updates are being dispatched.
+///
+/// Every dispatch loads the current router when it starts and finishes on that router,
+/// even if it is replaced in the meantime.
+#[derive(Clone)]
+pub struct RouterHandle {
+    current: Arc<RwLock<Arc<Router>>>,
+    bot_username: Option<String>,
+}
 
-        self.emit(Event::EndDispatch { deps }).await;
+impl RouterHandle {
+    pub fn new(router: Arc<Router>) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(router)),
+            bot_username: None,
+        }
+    }
+
+    /// A handle whose routers match commands addressed as `/cmd@bot_username`.
+    pub(crate) fn for_bot(router: Arc<Router>, bot_username: Option<&str>) -> Self {
+        let handle = Self {
+            current: Arc::new(RwLock::new(router.clone())),
+            bot_username: bot_username.map(str::to_string),
+        };
+        if handle.bot_username.is_some() {
+            handle.replace(Router::clone(&router));
+        }
+        handle
+    }
+
+    pub fn load(&self) -> Arc<Router> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Swap in `router`, returning the one it replaced.
+    pub fn replace(&self, router: Router) -> Arc<Router> {
+        let router = self.prepare(router);
+        std::mem::replace(&mut *self.current.write().unwrap(), router)
+    }
+
+    /// Swap in a copy of the current router modified by `patch`.
+    ///
+    /// Concurrent patches are applied one after another, so none of them is lost.
+    pub fn patch<F: FnOnce(&mut Router)>(&self, patch: F) {
+        let mut current = self.current.write().unwrap();
+        let mut router = Router::clone(&current);
+        patch(&mut router);
+        *current = self.prepare(router);
+    }
+
+    fn prepare(&self, mut router: Router) -> Arc<Router> {
+        if let Some(username) = &self.bot_username {
+            router.reinit_command_regexes(username);
+        }
+        Arc::new(router)
     }
 }
 
@@ -88,3 +909,404 @@ impl Default for Router {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use grammers_client::grammers_tl_types as tl;
+
+    use super::*;
+    use crate::{errors::DispatchError, scope::Scoped};
+
+    fn update() -> Update {
+        Update::Raw(tl::enums::Update::DcOptions(tl::types::UpdateDcOptions {
+            dc_options: Vec::new(),
+        }))
+    }
+
+    /// A router whose scoped `u32` records how its finalizer was called.
+    fn transactional(finalized: &Arc<Mutex<Vec<bool>>>) -> Router {
+        let finalized = finalized.clone();
+        let mut router = Router::new();
+        router.add_factory_with_finalizer(
+            |_update| async { Ok(7u32) },
+            move |_value, result: Result<(), SharedError>| {
+                finalized.lock().unwrap().push(result.is_ok());
+                async { Ok(()) }
+            },
+        );
+        router
+    }
+
+    #[tokio::test]
+    async fn test_nested_failure_rolls_back() {
+        let finalized = Arc::new(Mutex::new(Vec::new()));
+        let mut inner = Router::new();
+        inner.add(dptree::endpoint(|scoped: Scoped<u32>| async move {
+            scoped.get().await?;
+            Err::<(), _>(Error::msg("insert failed"))
+        }));
+        let mut router = transactional(&finalized);
+        router.include(inner);
+
+        let mut deps = DependencyMap::new();
+        deps.insert(update());
+        let outcome = router.dispatch(deps).await;
+
+        assert_eq!(outcome.handlers.len(), 1);
+        assert_eq!(outcome.handlers[0].handler, HandlerPath(vec![0, 0]));
+        assert_eq!(outcome.errors().count(), 1);
+        assert_eq!(*finalized.lock().unwrap(), [false]);
+    }
+
+    #[tokio::test]
+    async fn test_nested_success_commits() {
+        let finalized = Arc::new(Mutex::new(Vec::new()));
+        let mut inner = Router::new();
+        inner.add(dptree::endpoint(|scoped: Scoped<u32>| async move {
+            scoped.get().await?;
+            Ok::<(), Error>(())
+        }));
+        let mut router = transactional(&finalized);
+        router.include(inner);
+
+        let mut deps = DependencyMap::new();
+        deps.insert(update());
+        let outcome = router.dispatch(deps).await;
+
+        assert!(outcome.matched());
+        assert_eq!(outcome.errors().count(), 0);
+        assert_eq!(*finalized.lock().unwrap(), [true]);
+    }
+
+    /// Records the events it sees as `name:kind`, breaking out of those `breaks` selects.
+    struct Recorder {
+        name: &'static str,
+        breaks: fn(&Event) -> bool,
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl EventListener for Recorder {
+        async fn handle(&self, event: &Event) -> ControlFlow<()> {
+            let kind = match event {
+                Event::UpdateReceived { .. } => "received",
+                Event::BeginDispatch { .. } => "begin",
+                Event::HandlerMatched { .. } => "matched",
+                Event::HandlerFinished { .. } => "finished",
+                Event::Unhandled { .. } => "unhandled",
+                Event::EndDispatch { .. } => "end",
+                _ => "other",
+            };
+            self.seen
+                .lock()
+                .unwrap()
+                .push(format!("{}:{}", self.name, kind));
+            if (self.breaks)(event) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        }
+    }
+
+    fn recorded(router: &mut Router, breaks: fn(&Event) -> bool) -> Arc<Mutex<Vec<String>>> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        router.add_event_listener(Recorder {
+            name: "first",
+            breaks,
+            seen: seen.clone(),
+        });
+        router.add_event_listener(Recorder {
+            name: "second",
+            breaks: |_| false,
+            seen: seen.clone(),
+        });
+        seen
+    }
+
+    #[tokio::test]
+    async fn test_begin_dispatch_break_cancels() {
+        let ran = Arc::new(AtomicBool::new(false));
+        let mut router = Router::new();
+        let flag = ran.clone();
+        router.add(dptree::endpoint(move || {
+            let flag = flag.clone();
+            async move {
+                flag.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+        }));
+        let seen = recorded(&mut router, |event| {
+            matches!(event, Event::BeginDispatch { .. })
+        });
+
+        let outcome = router.dispatch(dptree::deps![update()]).await;
+
+        assert!(outcome.cancelled);
+        assert!(!ran.load(Ordering::Relaxed));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["first:received", "second:received", "first:begin"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_other_events_do_not_short_circuit() {
+        let mut router = Router::new();
+        router.add(dptree::endpoint(|| async { Ok(()) }));
+        let seen = recorded(&mut router, |event| {
+            !matches!(event, Event::BeginDispatch { .. })
+        });
+
+        let outcome = router.dispatch(dptree::deps![update()]).await;
+
+        assert!(!outcome.cancelled);
+        assert!(outcome.matched());
+        let seen = seen.lock().unwrap();
+        for kind in ["received", "begin", "matched", "finished", "end"] {
+            assert!(
+                seen.contains(&format!("first:{kind}")),
+                "first missed {kind}"
+            );
+            assert!(
+                seen.contains(&format!("second:{kind}")),
+                "second missed {kind}"
+            );
+        }
+    }
+
+    fn ok() -> Handler<'static, DependencyMap, GenericResult> {
+        dptree::endpoint(|| async { Ok(()) })
+    }
+
+    fn unmatched() -> Handler<'static, DependencyMap, GenericResult> {
+        dptree::filter(|| false).endpoint(|| async { Ok(()) })
+    }
+
+    /// The handlers that were tried, in order, and whether each matched.
+    fn tried(outcome: &DispatchOutcome) -> Vec<(String, bool)> {
+        outcome
+            .handlers
+            .iter()
+            .map(|outcome| (outcome.handler.to_string(), outcome.matched()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_sequential_by_priority_until_match() {
+        let mut router = Router::new();
+        router.set_strategy(DispatchStrategy::Sequential);
+        router.add(ok());
+        router.add_with_priority(unmatched(), 10);
+        router.add_with_priority(ok(), 5);
+        router.add_with_priority(ok(), 5);
+
+        let outcome = router.dispatch(dptree::deps![update()]).await;
+
+        assert_eq!(
+            tried(&outcome),
+            [("1".to_string(), false), ("2".to_string(), true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_grouped_runs_one_handler_per_group() {
+        let mut router = Router::new();
+        router.set_strategy(DispatchStrategy::Grouped);
+        router.add_to_group(1, unmatched());
+        router.add_to_group(1, ok());
+        router.add_to_group(1, ok());
+        router.add_route(ok(), 0, 0);
+        router.add_route(ok(), 5, 0);
+
+        let outcome = router.dispatch(dptree::deps![update()]).await;
+
+        assert_eq!(
+            tried(&outcome),
+            [
+                ("4".to_string(), true),
+                ("0".to_string(), false),
+                ("1".to_string(), true),
+            ]
+        );
+    }
+
+    /// A router whose error handler collects the errors it receives, checking that the
+    /// update is still in the dependencies.
+    fn collecting(errors: &Arc<Mutex<Vec<SharedError>>>) -> Router {
+        let errors = errors.clone();
+        let mut router = Router::new();
+        router.set_error_handler(dptree::endpoint(
+            move |err: SharedError, _update: Update| {
+                errors.lock().unwrap().push(err);
+                async {}
+            },
+        ));
+        router
+    }
+
+    #[tokio::test]
+    async fn test_timeout_cancels_handler() {
+        tokio::time::pause();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let token = Arc::new(Mutex::new(None));
+        let mut inner = Router::new();
+        inner.set_timeout(Duration::from_secs(1));
+        let slot = token.clone();
+        inner.add(dptree::endpoint(move |token: CancellationToken| {
+            *slot.lock().unwrap() = Some(token);
+            async {
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+                Ok(())
+            }
+        }));
+        let mut router = collecting(&errors);
+        router.add(ok());
+        router.include(inner);
+
+        let outcome = router.dispatch(dptree::deps![update()]).await;
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            &*errors[0],
+            Error::Dispatch(DispatchError::Timeout { handler: HandlerId::Path(path), timeout })
+                if *path == HandlerPath(vec![1, 0]) && *timeout == Duration::from_secs(1)
+        ));
+        assert_eq!(outcome.errors().count(), 1);
+        assert!(token.lock().unwrap().as_ref().unwrap().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_panic_reaches_error_handler_until_limit() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let mut router = collecting(&errors);
+        router.set_panic_limit(1);
+        router.add(dptree::endpoint(|| async { panic!("boom") }));
+
+        let outcome = router.dispatch(dptree::deps![update()]).await;
+        assert_eq!(outcome.errors().count(), 1);
+        {
+            let errors = errors.lock().unwrap();
+            assert_eq!(errors.len(), 1);
+            assert!(matches!(
+                &*errors[0],
+                Error::Dispatch(DispatchError::Panic { handler: HandlerId::Path(path), message, .. })
+                    if *path == HandlerPath(vec![0]) && message == "boom"
+            ));
+        }
+
+        let outcome = router.dispatch(dptree::deps![update()]).await;
+        assert!(!outcome.matched());
+        assert_eq!(errors.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_router_skips_fallback() {
+        let fallbacks = Arc::new(AtomicUsize::new(0));
+        let mut router = Router::new();
+        router.add(unmatched());
+        let counter = fallbacks.clone();
+        router.set_fallback_handler(dptree::endpoint(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            async { Ok(()) }
+        }));
+        let seen = recorded(&mut router, |_| false);
+
+        router.toggle().disable();
+        let outcome = router.dispatch(dptree::deps![update()]).await;
+        assert!(outcome.fallback.is_none());
+        assert_eq!(fallbacks.load(Ordering::Relaxed), 0);
+        assert!(
+            !seen
+                .lock()
+                .unwrap()
+                .contains(&"first:unhandled".to_string())
+        );
+
+        router.toggle().enable();
+        let outcome = router.dispatch(dptree::deps![update()]).await;
+        assert!(outcome.fallback.is_some());
+        assert_eq!(fallbacks.load(Ordering::Relaxed), 1);
+        assert!(
+            seen.lock()
+                .unwrap()
+                .contains(&"first:unhandled".to_string())
+        );
+    }
+
+    fn recording(
+        seen: &Arc<Mutex<Vec<&'static str>>>,
+        name: &'static str,
+    ) -> Handler<'static, DependencyMap, GenericResult> {
+        let seen = seen.clone();
+        dptree::endpoint(move || {
+            seen.lock().unwrap().push(name);
+            async { Ok(()) }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_dispatch_keeps_old_router() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let started = Arc::new(tokio::sync::Notify::new());
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+
+        let mut old = Router::new();
+        let (signal, wait) = (started.clone(), gate.clone());
+        old.add(dptree::endpoint(move || {
+            let (signal, wait) = (signal.clone(), wait.clone());
+            async move {
+                signal.notify_one();
+                let _ = wait.acquire().await;
+                Ok(())
+            }
+        }));
+        let handle = RouterHandle::new(Arc::new(old));
+
+        let router = handle.load();
+        let in_flight = tokio::spawn(async move { router.dispatch(dptree::deps![update()]).await });
+        started.notified().await;
+
+        let mut new = Router::new();
+        new.add(recording(&seen, "new"));
+        handle.replace(new);
+        handle.patch(|router| {
+            router.add(recording(&seen, "patched"));
+        });
+        gate.add_permits(1);
+
+        let outcome = in_flight.await.unwrap();
+        assert_eq!(outcome.handlers.len(), 1);
+        assert!(seen.lock().unwrap().is_empty());
+
+        let outcome = handle.load().dispatch(dptree::deps![update()]).await;
+        assert_eq!(outcome.handlers.len(), 2);
+        seen.lock().unwrap().sort();
+        assert_eq!(*seen.lock().unwrap(), ["new", "patched"]);
+    }
+
+    #[test]
+    fn test_concurrent_patches_are_all_applied() {
+        let handle = RouterHandle::new(Arc::new(Router::new()));
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                let handle = handle.clone();
+                std::thread::spawn(move || {
+                    handle.patch(|router| {
+                        router.add(ok());
+                    })
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(handle.load().handlers.len(), 16);
+    }
+
+    #[test]
+    fn test_handler_path_display() {
+        assert_eq!(HandlerPath(vec![2, 0, 1]).to_string(), "2.0.1");
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use dptree::di::DependencyMap;
+use futures::{FutureExt, future::BoxFuture};
+use grammers_client::Update;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::{Error, GenericResult, SharedError};
+
+type Create<T> = Arc<dyn Fn(Update) -> BoxFuture<'static, Result<T, Error>> + Send + Sync>;
+type Finalizer<T> =
+    Arc<dyn Fn(T, Result<(), SharedError>) -> BoxFuture<'static, GenericResult> + Send + Sync>;
+
+struct State<T> {
+    update: Option<Update>,
+    create: Create<T>,
+    value: Mutex<Option<Arc<T>>>,
+}
+
+/// A value created on first use and shared by every handler of a single dispatch.
+///
+/// Request `Scoped<T>` in a handler to use a factory registered with
+/// [`Router::add_factory`](crate::Router::add_factory). The factory does not run unless a
+/// handler calls [`Scoped::get`].
+pub struct Scoped<T> {
+    state: Arc<State<T>>,
+}
+
+impl<T> Clone for Scoped<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> Scoped<T> {
+    /// The value for this dispatch, running the factory if no handler did yet.
+    ///
+    /// A failed factory is run again by the next call.
+    pub async fn get(&self) -> Result<Arc<T>, Error> {
+        let mut value = self.state.value.lock().await;
+        if let Some(value) = value.as_ref() {
+            return Ok(value.clone());
+        }
+
+        let update = self.state.update.clone().ok_or_else(|| {
+            Error::Internal(anyhow::anyhow!("no update to create a scoped value from"))
+        })?;
+        let created = Arc::new((self.state.create)(update).await?);
+        *value = Some(created.clone());
+        Ok(created)
+    }
+}
+
+/// A registered factory, type-erased so routers can hold factories of any type.
+pub(crate) trait Factory: Send + Sync + 'static {
+    /// Insert a fresh [`Scoped`] into `deps`, returning what finalizes it.
+    fn begin(&self, update: Option<Update>, deps: &mut DependencyMap) -> Box<dyn Finalize>;
+}
+
+#[async_trait]
+pub(crate) trait Finalize: Send {
+    /// Run the finalizer if the value was created. `result` is the result of the whole
+    /// dispatch, see [`Router::add_factory_with_finalizer`](crate::Router::add_factory_with_finalizer).
+    async fn finalize(self: Box<Self>, result: Result<(), SharedError>);
+}
+
+struct TypedFactory<T> {
+    create: Create<T>,
+    finalizer: Option<Finalizer<T>>,
+}
+
+impl<T: Send + Sync + 'static> Factory for TypedFactory<T> {
+    fn begin(&self, update: Option<Update>, deps: &mut DependencyMap) -> Box<dyn Finalize> {
+        let scoped = Scoped {
+            state: Arc::new(State {
+                update,
+                create: self.create.clone(),
+                value: Mutex::new(None),
+            }),
+        };
+        deps.insert(scoped.clone());
+        Box::new(Pending {
+            scoped,
+            finalizer: self.finalizer.clone(),
+        })
+    }
+}
+
+struct Pending<T> {
+    scoped: Scoped<T>,
+    finalizer: Option<Finalizer<T>>,
+}
+
+#[async_trait]
+impl<T: Send + Sync + 'static> Finalize for Pending<T> {
+    async fn finalize(self: Box<Self>, result: Result<(), SharedError>) {
+        let Some(finalizer) = self.finalizer else {
+            return;
+        };
+        let Some(value) = self.scoped.state.value.lock().await.take() else {
+            return;
+        };
+        match Arc::try_unwrap(value) {
+            Ok(value) => {
+                if let Err(err) = finalizer(value, result).await {
+                    warn!("scoped value finalizer failed: {err}");
+                }
+            }
+            Err(_) => warn!(
+                "scoped {} outlived its dispatch; finalizer skipped",
+                std::any::type_name::<T>()
+            ),
+        }
+    }
+}
+
+pub(crate) fn factory<T, F, Fut>(create: F) -> Arc<dyn Factory>
+where
+    T: Send + Sync + 'static,
+    F: Fn(Update) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T, Error>> + Send + 'static,
+{
+    Arc::new(TypedFactory {
+        create: Arc::new(move |update| create(update).boxed()),
+        finalizer: None,
+    })
+}
+
+pub(crate) fn factory_with_finalizer<T, F, Fut, G, GFut>(
+    create: F,
+    finalizer: G,
+) -> Arc<dyn Factory>
+where
+    T: Send + Sync + 'static,
+    F: Fn(Update) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T, Error>> + Send + 'static,
+    G: Fn(T, Result<(), SharedError>) -> GFut + Send + Sync + 'static,
+    GFut: Future<Output = GenericResult> + Send + 'static,
+{
+    Arc::new(TypedFactory {
+        create: Arc::new(move |update| create(update).boxed()),
+        finalizer: Some(Arc::new(move |value, result| {
+            finalizer(value, result).boxed()
+        })),
+    })
+}
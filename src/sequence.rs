@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use grammers_client::types::Update;
+use tokio::sync::mpsc;
+
+/// What updates are serialized by in ordered processing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderKey {
+    /// Updates from the same chat are processed one at a time.
+    Chat,
+    /// Updates from the same user in the same chat are processed one at a time.
+    ChatAndUser,
+}
+
+impl OrderKey {
+    /// The key `update` is queued under, or `None` if it can be processed right away.
+    fn of(self, update: &Update) -> Option<(i64, Option<i64>)> {
+        let (chat_id, user_id) = match update {
+            Update::NewMessage(message) | Update::MessageEdited(message) => (
+                message.chat().id(),
+                message.sender().map(|sender| sender.id()),
+            ),
+            Update::CallbackQuery(query) => (query.chat().id(), Some(query.sender().id())),
+            _ => return None,
+        };
+        match self {
+            OrderKey::Chat => Some((chat_id, None)),
+            OrderKey::ChatAndUser => Some((chat_id, user_id)),
+        }
+    }
+}
+
+type Job = BoxFuture<'static, ()>;
+
+type Key = (i64, Option<i64>);
+
+struct Queue {
+    sender: mpsc::Sender<Job>,
+    /// Held by the task draining the key's queue. A queue that replaces an idle one
+    /// waits for it, so jobs sent while the old one was closing still run first.
+    turn: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Runs jobs of the same key in the order they were pushed, one at a time.
+///
+/// Every key has a bounded queue drained by its own task; the task exits once its queue
+/// was empty for `idle_timeout`.
+pub(crate) struct Sequencer {
+    key: OrderKey,
+    queue_size: usize,
+    idle_timeout: Duration,
+    queues: Mutex<HashMap<Key, Queue>>,
+}
+
+impl Sequencer {
+    pub(crate) fn new(key: OrderKey, queue_size: usize, idle_timeout: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            key,
+            queue_size: queue_size.max(1),
+            idle_timeout,
+            queues: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Queue `job` behind earlier jobs for the same update key.
+    ///
+    /// Waits while the key's queue is full. Updates without a key are spawned directly.
+    pub(crate) async fn push(self: &Arc<Self>, update: &Update, job: Job) {
+        match self.key.of(update) {
+            Some(key) => self.push_keyed(key, job).await,
+            None => {
+                tokio::spawn(job);
+            }
+        }
+    }
+
+    async fn push_keyed(self: &Arc<Self>, key: Key, job: Job) {
+        let mut job = job;
+        loop {
+            let sender = self.sender(key);
+            match sender.send(job).await {
+                Ok(()) => return,
+                // The queue went idle and closed after we picked it up; start a new one.
+                Err(mpsc::error::SendError(rejected)) => job = rejected,
+            }
+        }
+    }
+
+    fn sender(self: &Arc<Self>, key: Key) -> mpsc::Sender<Job> {
+        let mut queues = self.queues.lock().unwrap();
+        let turn = match queues.get(&key) {
+            Some(queue) if !queue.sender.is_closed() => return queue.sender.clone(),
+            Some(queue) => queue.turn.clone(),
+            None => Arc::default(),
+        };
+
+        let (sender, receiver) = mpsc::channel(self.queue_size);
+        queues.insert(
+            key,
+            Queue {
+                sender: sender.clone(),
+                turn: turn.clone(),
+            },
+        );
+        tokio::spawn(self.clone().drain(key, sender.clone(), receiver, turn));
+        sender
+    }
+
+    async fn drain(
+        self: Arc<Self>,
+        key: Key,
+        sender: mpsc::Sender<Job>,
+        mut receiver: mpsc::Receiver<Job>,
+        turn: Arc<tokio::sync::Mutex<()>>,
+    ) {
+        let _turn = turn.lock().await;
+        loop {
+            match tokio::time::timeout(self.idle_timeout, receiver.recv()).await {
+                Ok(Some(job)) => job.await,
+                Ok(None) => break,
+                Err(_) => {
+                    // Close first: whatever was sent before that still runs here, in
+                    // order, and later jobs go to a new queue that waits for our turn.
+                    receiver.close();
+                    while let Some(job) = receiver.recv().await {
+                        job.await;
+                    }
+                    break;
+                }
+            }
+        }
+
+        // Keep the entry until the tail ran, so a queue started meanwhile waits for it.
+        let mut queues = self.queues.lock().unwrap();
+        if queues
+            .get(&key)
+            .is_some_and(|queue| queue.sender.same_channel(&sender))
+        {
+            queues.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    /// A job that records `id` and fails the test if another job of the key is running.
+    fn job(id: usize, log: &Arc<Mutex<Vec<usize>>>, busy: &Arc<AtomicBool>) -> Job {
+        let log = log.clone();
+        let busy = busy.clone();
+        Box::pin(async move {
+            assert!(!busy.swap(true, Ordering::SeqCst), "jobs overlapped");
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            log.lock().unwrap().push(id);
+            busy.store(false, Ordering::SeqCst);
+        })
+    }
+
+    #[tokio::test]
+    async fn test_runs_jobs_in_order() {
+        let sequencer = Sequencer::new(OrderKey::Chat, 4, Duration::from_secs(1));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let busy = Arc::new(AtomicBool::new(false));
+        for id in 0..20 {
+            sequencer.push_keyed((1, None), job(id, &log, &busy)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(*log.lock().unwrap(), (0..20).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_evicts_idle_queues() {
+        let sequencer = Sequencer::new(OrderKey::Chat, 4, Duration::from_millis(20));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let busy = Arc::new(AtomicBool::new(false));
+        sequencer.push_keyed((1, None), job(0, &log, &busy)).await;
+        assert_eq!(sequencer.queues.lock().unwrap().len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sequencer.queues.lock().unwrap().is_empty());
+
+        sequencer.push_keyed((1, None), job(1, &log, &busy)).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*log.lock().unwrap(), [0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_keeps_order_across_idle_close() {
+        let sequencer = Sequencer::new(OrderKey::Chat, 1, Duration::from_millis(1));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let busy = Arc::new(AtomicBool::new(false));
+        for id in 0..50 {
+            sequencer.push_keyed((1, None), job(id, &log, &busy)).await;
+            if id % 5 == 0 {
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(*log.lock().unwrap(), (0..50).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_keys_run_independently() {
+        let sequencer = Sequencer::new(OrderKey::ChatAndUser, 4, Duration::from_secs(1));
+        let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+        let done = Arc::new(AtomicBool::new(false));
+        let finished = done.clone();
+        // The first key waits for a job of the second one.
+        sequencer
+            .push_keyed(
+                (1, Some(1)),
+                Box::pin(async move {
+                    let _ = receiver.await;
+                    finished.store(true, Ordering::SeqCst);
+                }),
+            )
+            .await;
+        sequencer
+            .push_keyed(
+                (1, Some(2)),
+                Box::pin(async move {
+                    let _ = sender.send(());
+                }),
+            )
+            .await;
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !done.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
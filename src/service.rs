@@ -0,0 +1,309 @@
+use grammers_client::{grammers_tl_types as tl, types::Message};
+
+/// A service message decoded from its [`tl::enums::MessageAction`].
+pub trait ServiceEvent: Sized {
+    fn from_action(action: &tl::enums::MessageAction, context: &ServiceContext) -> Option<Self>;
+
+    fn from_message(message: &Message) -> Option<Self> {
+        Self::from_action(message.action()?, &ServiceContext::of(message))
+    }
+}
+
+/// The parts of a service message that its action does not carry.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceContext {
+    pub sender_id: Option<i64>,
+    pub reply_to_message_id: Option<i32>,
+}
+
+impl ServiceContext {
+    pub fn of(message: &Message) -> Self {
+        Self {
+            sender_id: message.sender().map(|sender| sender.id()),
+            reply_to_message_id: message.reply_to_message_id(),
+        }
+    }
+}
+
+/// How members ended up in the chat.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinMethod {
+    /// Added by another member, or joined a public group on their own when `by` is one
+    /// of the joined users.
+    Added {
+        by: Option<i64>,
+    },
+    InviteLink {
+        inviter_id: i64,
+    },
+    Request,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemberJoined {
+    pub user_ids: Vec<i64>,
+    pub method: JoinMethod,
+}
+
+impl ServiceEvent for MemberJoined {
+    fn from_action(action: &tl::enums::MessageAction, context: &ServiceContext) -> Option<Self> {
+        use tl::enums::MessageAction as A;
+
+        match action {
+            A::ChatAddUser(action) => Some(Self {
+                user_ids: action.users.clone(),
+                method: JoinMethod::Added {
+                    by: context.sender_id,
+                },
+            }),
+            A::ChatJoinedByLink(action) => Some(Self {
+                user_ids: context.sender_id.into_iter().collect(),
+                method: JoinMethod::InviteLink {
+                    inviter_id: action.inviter_id,
+                },
+            }),
+            A::ChatJoinedByRequest => Some(Self {
+                user_ids: context.sender_id.into_iter().collect(),
+                method: JoinMethod::Request,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemberLeft {
+    pub user_id: i64,
+    /// Who removed the member, if they did not leave on their own.
+    pub removed_by: Option<i64>,
+}
+
+impl ServiceEvent for MemberLeft {
+    fn from_action(action: &tl::enums::MessageAction, context: &ServiceContext) -> Option<Self> {
+        match action {
+            tl::enums::MessageAction::ChatDeleteUser(action) => Some(Self {
+                user_id: action.user_id,
+                removed_by: context.sender_id.filter(|&id| id != action.user_id),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatTitleChanged {
+    pub title: String,
+    pub changed_by: Option<i64>,
+}
+
+impl ServiceEvent for ChatTitleChanged {
+    fn from_action(action: &tl::enums::MessageAction, context: &ServiceContext) -> Option<Self> {
+        match action {
+            tl::enums::MessageAction::ChatEditTitle(action) => Some(Self {
+                title: action.title.clone(),
+                changed_by: context.sender_id,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatPhotoChanged {
+    /// The new photo, or `None` if it was removed.
+    pub photo: Option<tl::enums::Photo>,
+    pub changed_by: Option<i64>,
+}
+
+impl ServiceEvent for ChatPhotoChanged {
+    fn from_action(action: &tl::enums::MessageAction, context: &ServiceContext) -> Option<Self> {
+        use tl::enums::MessageAction as A;
+
+        let photo = match action {
+            A::ChatEditPhoto(action) => Some(action.photo.clone()),
+            A::ChatDeletePhoto => None,
+            _ => return None,
+        };
+        Some(Self {
+            photo,
+            changed_by: context.sender_id,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PinnedMessage {
+    /// Id of the message that was pinned.
+    pub message_id: Option<i32>,
+    pub pinned_by: Option<i64>,
+}
+
+impl ServiceEvent for PinnedMessage {
+    fn from_action(action: &tl::enums::MessageAction, context: &ServiceContext) -> Option<Self> {
+        match action {
+            tl::enums::MessageAction::PinMessage => Some(Self {
+                message_id: context.reply_to_message_id,
+                pinned_by: context.sender_id,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A basic group was upgraded to a supergroup.
+///
+/// Telegram posts one service message in each chat: `To` in the old group and `From` in
+/// the new supergroup.
+#[derive(Debug, Clone)]
+pub enum ChatMigrated {
+    To { channel_id: i64 },
+    From { chat_id: i64, title: String },
+}
+
+impl ServiceEvent for ChatMigrated {
+    fn from_action(action: &tl::enums::MessageAction, context: &ServiceContext) -> Option<Self> {
+        use tl::enums::MessageAction as A;
+
+        match action {
+            A::ChatMigrateTo(action) => Some(Self::To {
+                channel_id: action.channel_id,
+            }),
+            A::ChannelMigrateFrom(action) => Some(Self::From {
+                chat_id: action.chat_id,
+                title: action.title.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum GroupCallEvent {
+    Started,
+    Ended { duration: i32 },
+    Scheduled { schedule_date: i32 },
+    Invited { user_ids: Vec<i64> },
+}
+
+impl ServiceEvent for GroupCallEvent {
+    fn from_action(action: &tl::enums::MessageAction, context: &ServiceContext) -> Option<Self> {
+        use tl::enums::MessageAction as A;
+
+        match action {
+            A::GroupCall(action) => Some(match action.duration {
+                Some(duration) => Self::Ended { duration },
+                None => Self::Started,
+            }),
+            A::GroupCallScheduled(action) => Some(Self::Scheduled {
+                schedule_date: action.schedule_date,
+            }),
+            A::InviteToGroupCall(action) => Some(Self::Invited {
+                user_ids: action.users.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatCreated {
+    pub title: String,
+    /// Initial members; empty for channels.
+    pub user_ids: Vec<i64>,
+}
+
+impl ServiceEvent for ChatCreated {
+    fn from_action(action: &tl::enums::MessageAction, context: &ServiceContext) -> Option<Self> {
+        use tl::enums::MessageAction as A;
+
+        match action {
+            A::ChatCreate(action) => Some(Self {
+                title: action.title.clone(),
+                user_ids: action.users.clone(),
+            }),
+            A::ChannelCreate(action) => Some(Self {
+                title: action.title.clone(),
+                user_ids: Vec::new(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent_by(sender_id: i64) -> ServiceContext {
+        ServiceContext {
+            sender_id: Some(sender_id),
+            ..Default::default()
+        }
+    }
+
+    fn delete_user(user_id: i64) -> tl::enums::MessageAction {
+        tl::types::MessageActionChatDeleteUser { user_id }.into()
+    }
+
+    #[test]
+    fn test_member_left_on_their_own() {
+        let left = MemberLeft::from_action(&delete_user(42), &sent_by(42)).unwrap();
+        assert_eq!(left.user_id, 42);
+        assert_eq!(left.removed_by, None);
+    }
+
+    #[test]
+    fn test_member_kicked() {
+        let left = MemberLeft::from_action(&delete_user(42), &sent_by(7)).unwrap();
+        assert_eq!(left.user_id, 42);
+        assert_eq!(left.removed_by, Some(7));
+    }
+
+    #[test]
+    fn test_join_methods() {
+        let added = MemberJoined::from_action(
+            &tl::types::MessageActionChatAddUser {
+                users: vec![42, 43],
+            }
+            .into(),
+            &sent_by(7),
+        )
+        .unwrap();
+        assert_eq!(added.user_ids, [42, 43]);
+        assert_eq!(added.method, JoinMethod::Added { by: Some(7) });
+
+        let by_link = MemberJoined::from_action(
+            &tl::types::MessageActionChatJoinedByLink { inviter_id: 7 }.into(),
+            &sent_by(42),
+        )
+        .unwrap();
+        assert_eq!(by_link.user_ids, [42]);
+        assert_eq!(by_link.method, JoinMethod::InviteLink { inviter_id: 7 });
+
+        let by_request =
+            MemberJoined::from_action(&tl::enums::MessageAction::ChatJoinedByRequest, &sent_by(42))
+                .unwrap();
+        assert_eq!(by_request.user_ids, [42]);
+        assert_eq!(by_request.method, JoinMethod::Request);
+    }
+
+    #[test]
+    fn test_other_actions_are_ignored() {
+        assert!(MemberJoined::from_action(&delete_user(42), &sent_by(42)).is_none());
+        assert!(
+            MemberLeft::from_action(&tl::enums::MessageAction::PinMessage, &sent_by(42)).is_none()
+        );
+    }
+
+    #[test]
+    fn test_pinned_message_uses_reply() {
+        let context = ServiceContext {
+            sender_id: Some(7),
+            reply_to_message_id: Some(100),
+        };
+        let pinned =
+            PinnedMessage::from_action(&tl::enums::MessageAction::PinMessage, &context).unwrap();
+        assert_eq!(pinned.message_id, Some(100));
+        assert_eq!(pinned.pinned_by, Some(7));
+    }
+}
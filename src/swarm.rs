@@ -1,20 +1,52 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
 use dptree::di::DependencyMap;
-use grammers_client::{Client, InvocationError, types::User};
-use tokio::sync;
+use futures::{FutureExt, future};
+use grammers_client::{
+    Client, InvocationError,
+    types::{Update, User},
+};
+use tokio::sync::{self, Semaphore};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 
-use crate::router::Router;
+use crate::{
+    Event,
+    album::{AlbumBuffer, MediaGroup},
+    concurrency::{Limiter, SaturationPolicy, Start},
+    permissions,
+    raw::RawChats,
+    replies::Replies,
+    router::{DispatchOutcome, Router, RouterHandle},
+    sequence::{OrderKey, Sequencer},
+};
 
 pub struct Swarm {
     objects: Vec<SwarmObject>,
+    concurrency_limit: Option<usize>,
+    stats: Arc<SwarmStats>,
 }
 
+/// The account a client is logged in as, injected into every dispatch.
+#[derive(Debug, Clone)]
+pub struct Me(pub User);
+
 pub struct SwarmObject {
     pub client: Arc<Client>,
-    pub router: Arc<Router>,
+    router: RouterHandle,
     pub deps: DependencyMap,
+    raw_updates: bool,
+    media_groups: Option<Duration>,
+    ordering: Option<(OrderKey, usize, Duration)>,
+    concurrency_limit: Option<usize>,
+    saturation_policy: SaturationPolicy,
+    replies: Arc<Replies>,
     me: User,
 }
 
@@ -25,47 +57,292 @@ impl SwarmObject {
         deps: DependencyMap,
     ) -> Result<Self, InvocationError> {
         let me = client.get_me().await?;
+        let router = RouterHandle::for_bot(router, me.username());
         Ok(Self {
             client,
             router,
             deps,
+            raw_updates: false,
+            media_groups: None,
+            ordering: None,
+            concurrency_limit: None,
+            saturation_policy: SaturationPolicy::default(),
+            replies: Arc::default(),
             me,
         })
     }
+
+    /// A handle to replace or patch this client's router while the swarm is running.
+    pub fn router(&self) -> RouterHandle {
+        self.router.clone()
+    }
+
+    /// Read raw updates instead of high-level ones.
+    ///
+    /// Every update is then delivered as [`Update::Raw`] together with its [`RawChats`],
+    /// including new messages and callback queries: handlers of this client that expect a
+    /// [`Message`](grammers_client::types::Message) or another high-level update never
+    /// match, and only [`filter_raw`](crate::filters::filter_raw) handlers run.
+    ///
+    /// [`Swarm::run`] refuses to start a client that reads raw updates and also groups
+    /// [`media_groups`](Self::media_groups) or [`ordered`](Self::ordered) updates, as both
+    /// need high-level messages.
+    pub fn raw_updates(mut self, yes: bool) -> Self {
+        self.raw_updates = yes;
+        self
+    }
+
+    /// Deliver albums as a single update carrying a [`MediaGroup`].
+    ///
+    /// Parts of an album are held back until no new part arrived for `window`, then
+    /// dispatched once as the first message of the album.
+    pub fn media_groups(mut self, window: Duration) -> Self {
+        self.media_groups = Some(window);
+        self
+    }
+
+    /// Process updates sharing a `key` one at a time, in the order they arrived.
+    ///
+    /// Updates with different keys still run in parallel. Each key queues up to
+    /// `queue_size` updates, after which reading new updates waits; a key's queue is
+    /// dropped once it was idle for `idle_timeout`.
+    pub fn ordered(mut self, key: OrderKey, queue_size: usize, idle_timeout: Duration) -> Self {
+        self.ordering = Some((key, queue_size, idle_timeout));
+        self
+    }
+
+    /// Run at most `limit` dispatches of this client at once.
+    pub fn concurrency_limit(mut self, limit: usize) -> Self {
+        self.concurrency_limit = Some(limit);
+        self
+    }
+
+    /// What to do with updates of this client while its own or the swarm-wide
+    /// concurrency limit is reached.
+    pub fn saturation_policy(mut self, policy: SaturationPolicy) -> Self {
+        self.saturation_policy = policy;
+        self
+    }
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.raw_updates && self.media_groups.is_some() {
+            anyhow::bail!("media_groups needs high-level updates, but raw_updates is set");
+        }
+        if self.raw_updates && self.ordering.is_some() {
+            anyhow::bail!("ordered needs high-level updates, but raw_updates is set");
+        }
+        Ok(())
+    }
+}
+
+/// Counters aggregated from the [`DispatchOutcome`]s of every client in a [`Swarm`].
+#[derive(Debug, Default)]
+pub struct SwarmStats {
+    updates: AtomicU64,
+    dropped: AtomicU64,
+    cancelled: AtomicU64,
+    unhandled: AtomicU64,
+    handler_runs: AtomicU64,
+    handler_errors: AtomicU64,
+    handler_time_us: AtomicU64,
+}
+
+impl SwarmStats {
+    /// Updates read from all clients.
+    pub fn updates(&self) -> u64 {
+        self.updates.load(Ordering::Relaxed)
+    }
+
+    /// Updates dropped by the [`SaturationPolicy`] of their client.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Dispatches cancelled by an event listener.
+    pub fn cancelled(&self) -> u64 {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Dispatches in which no handler matched.
+    pub fn unhandled(&self) -> u64 {
+        self.unhandled.load(Ordering::Relaxed)
+    }
+
+    /// Handlers that matched, across all dispatches. Handlers of included routers are
+    /// counted one by one, not as the router.
+    pub fn handler_runs(&self) -> u64 {
+        self.handler_runs.load(Ordering::Relaxed)
+    }
+
+    pub fn handler_errors(&self) -> u64 {
+        self.handler_errors.load(Ordering::Relaxed)
+    }
+
+    /// Total time spent in handlers that matched.
+    pub fn handler_time(&self) -> Duration {
+        Duration::from_micros(self.handler_time_us.load(Ordering::Relaxed))
+    }
+
+    fn record(&self, outcome: &DispatchOutcome) {
+        if outcome.cancelled {
+            self.cancelled.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if !outcome.matched() {
+            self.unhandled.fetch_add(1, Ordering::Relaxed);
+        }
+        for handler in outcome.handlers.iter().filter(|handler| handler.matched()) {
+            self.handler_runs.fetch_add(1, Ordering::Relaxed);
+            self.handler_time_us
+                .fetch_add(handler.elapsed.as_micros() as u64, Ordering::Relaxed);
+        }
+        self.handler_errors
+            .fetch_add(outcome.errors().count() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Everything a client needs to hand an update to its router.
+#[derive(Clone)]
+struct Pipeline {
+    client: Arc<Client>,
+    router: RouterHandle,
+    sequencer: Option<Arc<Sequencer>>,
+    limiter: Arc<Limiter>,
+    stats: Arc<SwarmStats>,
+}
+
+impl Pipeline {
+    /// Dispatch `update` once the limiter admits it, queued behind earlier updates of the
+    /// same key if ordered processing is enabled.
+    ///
+    /// Ordered updates take their permits when their turn comes, so a key waiting for an
+    /// earlier update does not hold any.
+    async fn dispatch(&self, deps: DependencyMap, update: &Update) {
+        match &self.sequencer {
+            Some(sequencer) => {
+                let pipeline = self.clone();
+                let job = async move { pipeline.admit(deps, true).await }.boxed();
+                sequencer.push(update, job).await;
+            }
+            None => self.admit(deps, false).await,
+        }
+    }
+
+    /// Hand the update to the limiter. With `wait`, return only once the dispatch is
+    /// done or the update was dropped.
+    async fn admit(&self, deps: DependencyMap, wait: bool) {
+        let (done, finished) = sync::oneshot::channel();
+        let start: Start = {
+            let client = self.client.clone();
+            let handle = self.router.clone();
+            let mut deps = deps.clone();
+            let stats = self.stats.clone();
+            Box::new(move |permits| {
+                tokio::spawn(async move {
+                    let router = handle.load();
+                    let _ = deps.insert(router.retry_client((*client).clone()));
+                    let outcome = router.dispatch(deps).await;
+                    drop(permits);
+                    stats.record(&outcome);
+                    let _ = done.send(());
+                });
+                future::ready(()).boxed()
+            })
+        };
+
+        let dropped = self.limiter.submit(start, deps).await;
+        if !dropped.is_empty() {
+            let router = self.router.load();
+            for deps in &dropped {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                let _ = router.emit(Event::UpdateDropped { deps }).await;
+            }
+        }
+        if wait {
+            // An error means the update was dropped.
+            let _ = finished.await;
+        }
+    }
+}
+
+async fn next_update(client: &Client, raw: bool) -> Result<(Update, RawChats), InvocationError> {
+    if raw {
+        let (update, chats) = client.next_raw_update().await?;
+        Ok((Update::Raw(update), RawChats::new(chats)))
+    } else {
+        Ok((client.next_update().await?, RawChats::default()))
+    }
 }
 
 impl Swarm {
     pub fn new() -> Self {
         Self {
             objects: Vec::new(),
+            concurrency_limit: None,
+            stats: Arc::default(),
         }
     }
 
+    pub fn stats(&self) -> Arc<SwarmStats> {
+        self.stats.clone()
+    }
+
     pub fn add(&mut self, object: SwarmObject) -> &mut Self {
         self.objects.push(object);
         self
     }
 
+    /// The router handle of the client added at `index`, see [`SwarmObject::router`].
+    pub fn router(&self, index: usize) -> Option<RouterHandle> {
+        self.objects.get(index).map(SwarmObject::router)
+    }
+
+    /// Run at most `limit` dispatches at once across all clients.
+    pub fn set_concurrency_limit(&mut self, limit: usize) -> &mut Self {
+        self.concurrency_limit = Some(limit);
+        self
+    }
+
     pub async fn run(&self) -> anyhow::Result<()> {
+        for object in &self.objects {
+            object.validate()?;
+        }
+
         let (shutdown_tx, _) = sync::broadcast::channel::<()>(1);
+        // Handlers find a child of this token in their dependencies.
+        let shutdown_token = CancellationToken::new();
+        let global_limit = self
+            .concurrency_limit
+            .map(|limit| Arc::new(Semaphore::new(limit)));
 
         for object in &self.objects {
             let mut shutdown_rx = shutdown_tx.subscribe();
             let client = object.client.clone();
+            let raw_updates = object.raw_updates;
+            let albums: Option<Arc<AlbumBuffer>> = object.media_groups.map(AlbumBuffer::new);
+            let sequencer = object
+                .ordering
+                .map(|(key, queue_size, idle)| Sequencer::new(key, queue_size, idle));
+            let limiter = Limiter::new(
+                object.saturation_policy,
+                object.concurrency_limit,
+                global_limit.clone(),
+                shutdown_token.clone(),
+            );
 
-            let router = if let Some(username) = object.me.username() {
-                let mut router: Router = match Arc::try_unwrap(object.router.clone()) {
-                    Ok(r) => r,
-                    Err(shared_arc) => (*shared_arc).clone(),
-                };
-                router.reinit_command_regexes(username);
-                Arc::new(router)
-            } else {
-                object.router.clone()
+            let pipeline = Pipeline {
+                client: client.clone(),
+                router: object.router.clone(),
+                sequencer,
+                limiter,
+                stats: self.stats.clone(),
             };
 
             let mut deps = object.deps.clone();
             let _ = deps.insert(client.clone());
+            let _ = deps.insert(Me(object.me.clone()));
+            let _ = deps.insert(object.replies.clone());
+            let replies = object.replies.clone();
+            let _ = deps.insert(shutdown_token.child_token());
             tokio::spawn(async move {
                 loop {
                     tokio::select! {
@@ -76,17 +353,46 @@ impl Swarm {
                             break
                         },
 
-                        result = client.next_update() => {
+                        result = next_update(&client, raw_updates) => {
                             match result {
-                                Ok(update) => {
-                                    let _ = deps.insert(update);
-                                    let deps = deps.clone();
-                                    let router = router.clone();
-                                    tokio::spawn(async move {
-                                        router.dispatch(deps).await;
-                                    });
+                                Ok((update, chats)) => {
+                                    pipeline.stats.updates.fetch_add(1, Ordering::Relaxed);
+                                    permissions::observe_update(&update);
+                                    let _ = deps.insert(chats);
+                                    let _ = deps.insert(None::<MediaGroup>);
+                                    let _ = deps.insert(replies.observe(&update));
+
+                                    if let Some(albums) = &albums
+                                        && let Update::NewMessage(message) = &update
+                                        && message.grouped_id().is_some()
+                                    {
+                                        let mut deps = deps.clone();
+                                        let pipeline = pipeline.clone();
+                                        albums.push(message.clone(), move |group| {
+                                            let first =
+                                                Update::NewMessage(group.messages[0].clone());
+                                            let _ = deps.insert(first.clone());
+                                            let _ = deps.insert(Some(group));
+                                            tokio::spawn(async move {
+                                                pipeline.dispatch(deps, &first).await;
+                                            });
+                                        });
+                                        continue;
+                                    }
+
+                                    let _ = deps.insert(update.clone());
+                                    pipeline.dispatch(deps.clone(), &update).await;
+                                }
+                                Err(err) => {
+                                    error!("Client error: {}", err);
+                                    if !matches!(err, InvocationError::Rpc(_)) {
+                                        let _ = pipeline
+                                            .router
+                                            .load()
+                                            .emit(Event::ClientDisconnected { error: &err })
+                                            .await;
+                                    }
                                 }
-                                Err(err) => error!("Client error: {}", err),
                             }
                         }
                     }
@@ -96,6 +402,7 @@ impl Swarm {
 
         tokio::signal::ctrl_c().await?;
         shutdown_tx.send(())?;
+        shutdown_token.cancel();
 
         Ok(())
     }
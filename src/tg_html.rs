@@ -153,6 +153,12 @@ impl Deref for TgHtml {
     }
 }
 
+impl std::fmt::Display for TgHtml {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.content)
+    }
+}
+
 /// Implement AsRef<str> so that Html can be passed where an AsRef<str> is required.
 impl AsRef<str> for TgHtml {
     fn as_ref(&self) -> &str {
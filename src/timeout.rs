@@ -0,0 +1,57 @@
+use std::{ops::ControlFlow, time::Duration};
+
+use dptree::{Handler, di::DependencyMap};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    GenericResult,
+    errors::{DispatchError, HandlerId},
+};
+
+/// The cancellation token in `deps`, or a fresh one if there is none.
+pub(crate) fn token(deps: &DependencyMap) -> CancellationToken {
+    deps.clone()
+        .remove::<CancellationToken>()
+        .map_or_else(CancellationToken::new, |token| (*token).clone())
+}
+
+/// Run `future` for at most `timeout`, cancelling `token` if it takes longer.
+pub(crate) async fn run_with_timeout<F>(
+    handler: HandlerId,
+    timeout: Option<Duration>,
+    token: &CancellationToken,
+    future: F,
+) -> Result<F::Output, DispatchError>
+where
+    F: Future,
+{
+    let Some(timeout) = timeout else {
+        return Ok(future.await);
+    };
+    match tokio::time::timeout(timeout, future).await {
+        Ok(output) => Ok(output),
+        Err(_) => {
+            token.cancel();
+            Err(DispatchError::Timeout { handler, timeout })
+        }
+    }
+}
+
+/// Fail the rest of a command handler with [`DispatchError::Timeout`] if it runs longer
+/// than `timeout`.
+pub(crate) fn command_timeout<Output>(
+    command: &'static str,
+    timeout: Option<Duration>,
+) -> Handler<'static, DependencyMap, Output>
+where
+    Output: From<GenericResult> + Send + Sync + 'static,
+{
+    dptree::from_fn(move |deps: DependencyMap, cont| async move {
+        let token = token(&deps);
+        let handler = HandlerId::Command(command);
+        match run_with_timeout(handler, timeout, &token, cont(deps)).await {
+            Ok(flow) => flow,
+            Err(err) => ControlFlow::Break(GenericResult::Err(err.into()).into()),
+        }
+    })
+}